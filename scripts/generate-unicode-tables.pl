@@ -0,0 +1,226 @@
+#!/usr/bin/env perl
+# Writes the Unicode range tables in src/unicode_tables.rs from the Unicode
+# database that ships with Perl. Run from the repository root:
+#
+#     perl scripts/generate-unicode-tables.pl > src/unicode_tables.rs
+
+use strict;
+use warnings;
+use Unicode::Normalize qw(NFD);
+use Unicode::UCD qw(casefold prop_invlist prop_value_aliases prop_values);
+
+# (table name, Perl property name)
+my @perl_classes = (
+    ["PERL_DIGIT", "Digit"],
+    ["PERL_SPACE", "XPosixSpace"],
+    ["PERL_WORD", "Word"],
+);
+
+# Turns an inversion list into inclusive (start, end) ranges of chars,
+# leaving out the surrogates.
+sub ranges {
+    my @invlist = @_;
+    push @invlist, 0x110000 if @invlist % 2;
+    my @ranges;
+    for (my $i = 0; $i < @invlist; $i += 2) {
+        my ($start, $end) = ($invlist[$i], $invlist[$i + 1] - 1);
+        if ($start < 0xD800 && $end >= 0xD800) {
+            push @ranges, [$start, 0xD7FF] if $start <= 0xD7FF;
+            $start = 0xE000;
+        }
+        $start = 0xE000 if $start >= 0xD800 && $start <= 0xDFFF;
+        $end = 0xD7FF if $end >= 0xD800 && $end <= 0xDFFF;
+        push @ranges, [$start, $end] if $start <= $end;
+    }
+    return @ranges;
+}
+
+# Turns a sorted list of chars into inclusive (start, end) ranges.
+sub char_ranges {
+    my @chars = @_;
+    my @ranges;
+    for my $c (@chars) {
+        if (@ranges && $ranges[-1][1] + 1 == $c) {
+            $ranges[-1][1] = $c;
+        } else {
+            push @ranges, [$c, $c];
+        }
+    }
+    return @ranges;
+}
+
+sub char_literal {
+    my ($c) = @_;
+    return sprintf("'%c'", $c)
+        if $c >= 0x20 && $c < 0x7F && $c != 0x27 && $c != 0x5C;
+    return sprintf("'\\u{%X}'", $c);
+}
+
+sub print_table {
+    my ($name, @ranges) = @_;
+    print "pub const $name: Table = &[\n";
+    my $line = "   ";
+    for my $range (@ranges) {
+        my $item = sprintf(" (%s, %s),", char_literal($range->[0]),
+                           char_literal($range->[1]));
+        if (length($line) + length($item) > 79) {
+            print "$line\n";
+            $line = "   ";
+        }
+        $line .= $item;
+    }
+    print "$line\n" if $line ne "   ";
+    print "];\n";
+}
+
+# Lowercases a property name and drops spaces, `_` and `-`, so that names
+# can be matched loosely as in UAX #44.
+sub normalize {
+    my ($name) = @_;
+    $name =~ s/[ _-]//g;
+    return lc $name;
+}
+
+printf "// Generated by scripts/generate-unicode-tables.pl from Unicode %s.\n",
+    Unicode::UCD::UnicodeVersion();
+print "// Do not edit by hand.\n";
+print "\n";
+print "/// A set of chars as sorted, non-overlapping inclusive ranges.\n";
+print "pub type Table = &'static [(char, char)];\n";
+
+for my $class (@perl_classes) {
+    my ($name, $property) = @$class;
+    print "\n";
+    print_table($name, ranges(prop_invlist($property)));
+}
+
+# Every property maps its loosely matched names to the tables that make it
+# up. One-letter general categories such as `L` are the union of their
+# two-letter subcategories, so only those get tables of their own.
+my %properties;
+my %has_table;
+my @categories = grep { length($_) == 2 && $_ ne "LC" } prop_values("gc");
+for my $category (sort @categories) {
+    my @ranges = ranges(prop_invlist("gc=$category"));
+    next unless @ranges;
+    my $table = "GC_" . uc $category;
+    $has_table{$table} = 1;
+    print "\n";
+    print_table($table, @ranges);
+}
+for my $category (prop_values("gc")) {
+    my @parts = $category eq "LC" ? ("Lu", "Ll", "Lt")
+        : length($category) == 1 ? grep { /^$category/ } @categories
+        : ($category);
+    my @tables = grep { $has_table{$_} } map { "GC_" . uc $_ } @parts;
+    for my $alias (prop_value_aliases("gc", $category)) {
+        $properties{normalize($alias)} = [@tables];
+    }
+}
+for my $script (sort(prop_values("sc"))) {
+    my @ranges = ranges(prop_invlist("sc=$script"));
+    next unless @ranges;
+    my @aliases = prop_value_aliases("sc", $script);
+    my $table = "SC_" . uc $aliases[1];
+    print "\n";
+    print_table($table, @ranges);
+    for my $alias (@aliases) {
+        my $existing = $properties{normalize($alias)};
+        die "duplicate property name $alias"
+            if $existing && $existing->[0] ne $table;
+        $properties{normalize($alias)} = [$table];
+    }
+}
+
+print "\n";
+print "/// The tables making up each general category and script, by name with\n";
+print "/// spaces, `_` and `-` removed and lowercased, sorted by name.\n";
+print "pub const PROPERTIES: &[(&str, &[Table])] = &[\n";
+for my $name (sort keys %properties) {
+    my $line = sprintf("    (\"%s\", &[%s]),", $name,
+                       join(", ", @{$properties{$name}}));
+    if (length($line) > 79) {
+        $line = sprintf("    (\"%s\",\n     &[%s]),", $name,
+                        join(", ", @{$properties{$name}}));
+    }
+    print "$line\n";
+}
+print "];\n";
+
+# Every letter that some char decomposes to followed by combining marks,
+# such as `e` for `é`, makes up an equivalence class with those chars.
+# Hangul syllables decompose to jamo rather than to accented letters.
+my %letters;
+for my $range (ranges(prop_invlist("L"))) {
+    $letters{$_} = 1 for $range->[0] .. $range->[1];
+}
+my %variants;
+for my $c (0 .. 0x10FFFF) {
+    next if $c >= 0xD800 && $c <= 0xDFFF;
+    next if $c >= 0xAC00 && $c <= 0xD7A3;
+    my $decomposition = NFD(chr $c);
+    next if length($decomposition) < 2;
+    my $base = ord $decomposition;
+    push @{$variants{$base}}, $c if $letters{$base};
+}
+
+print "\n";
+print "/// The chars in each equivalence class, by the letter the others are\n";
+print "/// made of, sorted by that letter.\n";
+print "pub const EQUIVALENCE_CLASSES: &[(char, Table)] = &[\n";
+for my $base (sort { $a <=> $b } keys %variants) {
+    my @chars = sort { $a <=> $b } ($base, @{$variants{$base}});
+    my $line = sprintf("    (%s, &[", char_literal($base));
+    my $separator = "";
+    for my $range (char_ranges(@chars)) {
+        my $item = sprintf("%s(%s, %s)", $separator,
+                           char_literal($range->[0]),
+                           char_literal($range->[1]));
+        if (length($line) + length($item) + 3 > 79) {
+            print "$line,\n";
+            $line = "        ";
+            $item =~ s/^, //;
+        }
+        $line .= $item;
+        $separator = ", ";
+    }
+    print "$line]),\n";
+}
+print "];\n";
+
+# Chars with the same simple case folding, such as `K`, `k` and the Kelvin
+# sign, are equal when case is ignored.
+my %orbits;
+for my $c (0 .. 0x10FFFF) {
+    my $folding = casefold($c);
+    next unless $folding && $folding->{simple} ne "";
+    my $folded = hex $folding->{simple};
+    next if $folded == $c;
+    $orbits{$folded}{$folded} = 1;
+    $orbits{$folded}{$c} = 1;
+}
+my %variants_of;
+for my $orbit (values %orbits) {
+    my @chars = sort { $a <=> $b } keys %$orbit;
+    for my $c (@chars) {
+        $variants_of{$c} = [grep { $_ != $c } @chars];
+    }
+}
+
+print "\n";
+print "/// The other chars each char is equal to when case is ignored, by simple\n";
+print "/// case folding, sorted by char.\n";
+print "pub const CASE_FOLDING: &[(char, &[char])] = &[\n";
+my $line = "   ";
+for my $c (sort { $a <=> $b } keys %variants_of) {
+    my $item = sprintf(" (%s, &[%s]),", char_literal($c),
+                       join(", ", map { char_literal($_) }
+                                  @{$variants_of{$c}}));
+    if (length($line) + length($item) > 79) {
+        print "$line\n";
+        $line = "   ";
+    }
+    $line .= $item;
+}
+print "$line\n" if $line ne "   ";
+print "];\n";
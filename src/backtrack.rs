@@ -0,0 +1,210 @@
+use class::eq_ignoring_case;
+use thompson_nfa::{assertion_holds, char_matches, lookbehind_start, num_slots,
+                   Anchoring, Inst};
+
+/// A unit of work for the backtracker. Alternatives not yet tried are kept
+/// on a stack together with the undo records for everything done since,
+/// so the search never recurses on the input.
+enum Job {
+    /// Continue matching at `pc` from byte offset `pos`.
+    Step { pc: usize, pos: usize },
+    /// Put back a capture slot overwritten by a `Save`.
+    RestoreSave { slot: usize, value: Option<usize> },
+    /// Put back every capture slot, after an atomic sub-program.
+    RestoreSaves(Vec<Option<usize>>),
+    /// Put back the record of where `pc` was last entered.
+    RestoreEntered { pc: usize, pos: Option<usize> },
+}
+
+/// Returns true if `insts` can only be run by `backtrack`, because it
+/// refers back to the text of a capture group.
+pub fn needs_backtracking(insts: &[Inst]) -> bool {
+    insts.iter().any(|inst| matches!(*inst, Inst::Backreference(_)
+                                           | Inst::FoldedBackreference { .. }))
+}
+
+/// Runs `insts` over `input` by backtracking and returns the capture slots
+/// of the leftmost match, laid out as by `thompson_vm`.
+///
+/// Unlike `thompson_vm` this supports `Backreference`, but it tries the
+/// alternatives of each `Split` one after the other, so it can take time
+/// exponential in the length of the input.
+pub fn backtrack(insts: &[Inst], captures_len: usize, input: &str,
+                 anchoring: Anchoring) -> Option<Vec<Option<usize>>> {
+    let mut starts = input.char_indices().map(|(i, _)| i)
+        .chain(Some(input.len()));
+    loop {
+        let start = starts.next()?;
+        let saves = vec![None; num_slots(insts, captures_len)];
+        if let Some((end, mut saves)) = run(insts, input, 0, start, None,
+                                            saves) {
+            saves[0] = Some(start);
+            saves[1] = Some(end);
+            return Some(saves);
+        }
+        if anchoring == Anchoring::Start {
+            return None;
+        }
+    }
+}
+
+/// Runs the program starting at `start_pc` anchored at byte offset
+/// `start_pos` of `input`, starting from the capture slots `saves`. Returns
+/// the end of the first match found, which is the preferred one, and its
+/// capture slots. If `must_end_at` is set, only matches ending there count.
+fn run(insts: &[Inst], input: &str, start_pc: usize, start_pos: usize,
+       must_end_at: Option<usize>, mut saves: Vec<Option<usize>>)
+       -> Option<(usize, Vec<Option<usize>>)> {
+    // Where each pc was entered on the current path, if it was entered at
+    // the position the path has reached. Entering it there again would
+    // loop without consuming input, which the Pike VM never follows either.
+    // Loops whose body can match empty repeat through a `Split` after it,
+    // so a path cut off here still leaves the loop through the other side
+    // of that `Split`.
+    let mut entered: Vec<Option<usize>> = vec![None; insts.len()];
+    let mut stack = vec![Job::Step { pc: start_pc, pos: start_pos }];
+
+    while let Some(job) = stack.pop() {
+        let (mut pc, mut pos) = match job {
+            Job::Step { pc, pos } => (pc, pos),
+            Job::RestoreSave { slot, value } => {
+                saves[slot] = value;
+                continue;
+            },
+            Job::RestoreSaves(old_saves) => {
+                saves = old_saves;
+                continue;
+            },
+            Job::RestoreEntered { pc, pos } => {
+                entered[pc] = pos;
+                continue;
+            }
+        };
+
+        // Follow this path until it fails or matches, leaving the other
+        // side of each `Split` on the stack.
+        loop {
+            if entered[pc] == Some(pos) { break; }
+            stack.push(Job::RestoreEntered { pc, pos: entered[pc] });
+            entered[pc] = Some(pos);
+
+            match insts[pc] {
+                Inst::Match => {
+                    if must_end_at.is_some_and(|end| end != pos) { break; }
+                    return Some((pos, saves));
+                },
+                Inst::Jump(jump_pc) => pc = jump_pc,
+                Inst::Split(s1_pc, s2_pc) => {
+                    stack.push(Job::Step { pc: s2_pc, pos });
+                    pc = s1_pc;
+                },
+                Inst::Save(slot) => {
+                    stack.push(Job::RestoreSave { slot, value: saves[slot] });
+                    saves[slot] = Some(pos);
+                    pc += 1;
+                },
+                Inst::Assert(assertion) => {
+                    let prev_char = input[..pos].chars().next_back();
+                    if !assertion_holds(assertion, input, pos, prev_char) {
+                        break;
+                    }
+                    pc += 1;
+                },
+                Inst::Atomic(next_pc) => {
+                    match run(insts, input, pc + 1, pos, None, saves.clone()) {
+                        Some((end, sub_saves)) => {
+                            let old_saves = ::std::mem::replace(&mut saves,
+                                                                sub_saves);
+                            stack.push(Job::RestoreSaves(old_saves));
+                            pc = next_pc;
+                            pos = end;
+                        },
+                        None => break
+                    }
+                },
+                Inst::Lookahead { negated, next }
+                    | Inst::Lookbehind { negated, next, .. } => {
+                    match (look(insts, input, pc, pos, &saves), negated) {
+                        (Some(sub_saves), false) => {
+                            let old_saves = ::std::mem::replace(&mut saves,
+                                                                sub_saves);
+                            stack.push(Job::RestoreSaves(old_saves));
+                        },
+                        (None, true) => (),
+                        _ => break
+                    }
+                    pc = next;
+                },
+                Inst::Backreference(group)
+                    | Inst::FoldedBackreference { group, .. } => {
+                    let span = (saves.get(2 * group), saves.get(2 * group + 1));
+                    let text = match span {
+                        (Some(&Some(start)), Some(&Some(end)))
+                            => &input[start..end],
+                        _ => break
+                    };
+                    let len = match insts[pc] {
+                        Inst::FoldedBackreference { unicode, .. }
+                            => prefix_len_ignoring_case(&input[pos..], text,
+                                                        unicode),
+                        _ => if input[pos..].starts_with(text) {
+                            Some(text.len())
+                        } else {
+                            None
+                        }
+                    };
+                    match len {
+                        Some(len) => pos += len,
+                        None => break
+                    }
+                    pc += 1;
+                },
+                ref inst => match input[pos..].chars().next() {
+                    Some(c) if char_matches(inst, c) => {
+                        pc += 1;
+                        pos += c.len_utf8();
+                    },
+                    _ => break
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Returns the length in bytes of the prefix of `input` that is equal to
+/// `text` when case is ignored, if there is one.
+fn prefix_len_ignoring_case(input: &str, text: &str, unicode: bool)
+                            -> Option<usize> {
+    let mut input_chars = input.char_indices();
+    for expected in text.chars() {
+        match input_chars.next() {
+            Some((_, c)) if eq_ignoring_case(c, expected, unicode) => (),
+            _ => return None
+        }
+    }
+    Some(input_chars.next().map_or(input.len(), |(i, _)| i))
+}
+
+/// Runs the lookaround sub-program of the `Lookahead` or `Lookbehind` at
+/// `pc` for byte offset `pos` of `input`, ignoring whether it is negated,
+/// and returns the capture slots of its match. A lookbehind tries each
+/// start in turn, from the furthest back.
+fn look(insts: &[Inst], input: &str, pc: usize, pos: usize,
+        saves: &[Option<usize>]) -> Option<Vec<Option<usize>>> {
+    match insts[pc] {
+        Inst::Lookbehind { min_len, max_len, .. } => {
+            let earliest = lookbehind_start(input, pos, max_len);
+            let latest = lookbehind_start(input, pos, min_len);
+            input[earliest..latest].char_indices().map(|(i, _)| earliest + i)
+                .chain(Some(latest))
+                .filter_map(|start| {
+                    run(insts, input, pc + 1, start, Some(pos), saves.to_vec())
+                })
+                .map(|(_, saves)| saves)
+                .next()
+        },
+        _ => run(insts, input, pc + 1, pos, None, saves.to_vec())
+            .map(|(_, saves)| saves)
+    }
+}
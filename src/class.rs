@@ -0,0 +1,274 @@
+use std::char;
+
+use unicode_tables;
+
+/// A set of chars stored as sorted, non-overlapping, non-adjacent inclusive
+/// ranges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharClass {
+    ranges: Vec<(char, char)>
+}
+
+impl CharClass {
+    /// Builds a class from ranges given in any order. Overlapping and
+    /// adjacent ranges are merged; ranges with `start > end` are ignored.
+    pub fn new(mut ranges: Vec<(char, char)>) -> CharClass {
+        ranges.retain(|&(start, end)| start <= end);
+        ranges.sort();
+        let mut merged: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            if let Some(last) = merged.last_mut() {
+                if last.1 >= start || next_char(last.1) == Some(start) {
+                    if end > last.1 { last.1 = end; }
+                    continue;
+                }
+            }
+            merged.push((start, end));
+        }
+        CharClass { ranges: merged }
+    }
+
+    /// The class containing no chars.
+    pub fn empty() -> CharClass {
+        CharClass { ranges: Vec::new() }
+    }
+
+    /// The sorted ranges making up this class.
+    pub fn ranges(&self) -> &[(char, char)] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns true if `c` is in this class, by binary search over the
+    /// ranges.
+    pub fn contains(&self, c: char) -> bool {
+        ranges_contain(&self.ranges, c)
+    }
+
+    /// Returns the class of every char not in this class.
+    pub fn negate(&self) -> CharClass {
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+        let mut next = Some('\0');
+        for &(start, end) in &self.ranges {
+            if let Some(gap_start) = next {
+                if let Some(gap_end) = prev_char(start) {
+                    if gap_start <= gap_end {
+                        ranges.push((gap_start, gap_end));
+                    }
+                }
+            }
+            next = next_char(end);
+        }
+        if let Some(gap_start) = next {
+            ranges.push((gap_start, char::MAX));
+        }
+        CharClass { ranges }
+    }
+
+    /// Returns the class of chars in either this class or `other`.
+    pub fn union(&self, other: &CharClass) -> CharClass {
+        let mut ranges = self.ranges.clone();
+        ranges.extend_from_slice(&other.ranges);
+        CharClass::new(ranges)
+    }
+
+    /// Returns the class of chars equal to a char in this class when case
+    /// is ignored, by Unicode simple case folding or, if `unicode` is false,
+    /// only between ASCII letters.
+    pub fn case_fold(&self, unicode: bool) -> CharClass {
+        let mut ranges = self.ranges.clone();
+        for &(start, end) in &self.ranges {
+            if unicode {
+                let table = unicode_tables::CASE_FOLDING;
+                let first = table.partition_point(|&(c, _)| c < start);
+                for &(_, variants) in table[first..].iter()
+                    .take_while(|&&(c, _)| c <= end) {
+                    ranges.extend(variants.iter().map(|&c| (c, c)));
+                }
+                continue;
+            }
+            for &(from, to) in &[(('a', 'z'), 'A'), (('A', 'Z'), 'a')] {
+                let (start, end) = (start.max(from.0), end.min(from.1));
+                if start <= end {
+                    let shift = |c: char| {
+                        (to as u8 + (c as u8 - from.0 as u8)) as char
+                    };
+                    ranges.push((shift(start), shift(end)));
+                }
+            }
+        }
+        CharClass::new(ranges)
+    }
+
+    /// Returns the class of chars in both this class and `other`.
+    pub fn intersection(&self, other: &CharClass) -> CharClass {
+        let mut ranges = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < self.ranges.len() && j < other.ranges.len() {
+            let (start1, end1) = self.ranges[i];
+            let (start2, end2) = other.ranges[j];
+            let (start, end) = (start1.max(start2), end1.min(end2));
+            if start <= end {
+                ranges.push((start, end));
+            }
+            // The range ending first cannot overlap anything further on.
+            if end1 < end2 { i += 1; } else { j += 1; }
+        }
+        CharClass { ranges }
+    }
+
+    /// Returns the class of chars in this class but not in `other`.
+    pub fn difference(&self, other: &CharClass) -> CharClass {
+        self.intersection(&other.negate())
+    }
+
+    /// Returns the class of chars in exactly one of this class and `other`.
+    pub fn symmetric_difference(&self, other: &CharClass) -> CharClass {
+        self.difference(other).union(&other.difference(self))
+    }
+}
+
+/// The predefined classes written `\d`, `\s` and `\w`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerlClass {
+    /// `\d`: decimal digits.
+    Digit,
+    /// `\s`: whitespace.
+    Space,
+    /// `\w`: word chars, meaning letters, marks, digits and connecting
+    /// punctuation such as `_`.
+    Word,
+}
+
+impl PerlClass {
+    /// Returns the chars in this class, either every one of them or only
+    /// the ASCII ones if `unicode` is false.
+    pub fn to_class(self, unicode: bool) -> CharClass {
+        use self::PerlClass::*;
+        let ranges: &[(char, char)] = match (self, unicode) {
+            (Digit, false) => &[('0', '9')],
+            (Space, false) => &[('\t', '\r'), (' ', ' ')],
+            (Word, false) => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+            (Digit, true) => unicode_tables::PERL_DIGIT,
+            (Space, true) => unicode_tables::PERL_SPACE,
+            (Word, true) => unicode_tables::PERL_WORD
+        };
+        CharClass { ranges: ranges.to_vec() }
+    }
+}
+
+/// Returns true if `c` is a word char as matched by `\w`, either in all of
+/// Unicode or only in ASCII if `unicode` is false.
+pub fn is_word_char(c: char, unicode: bool) -> bool {
+    if unicode {
+        ranges_contain(unicode_tables::PERL_WORD, c)
+    } else {
+        c.is_ascii_alphanumeric() || c == '_'
+    }
+}
+
+/// Returns the chars with the Unicode general category or script called
+/// `name`, such as `L`, `Uppercase_Letter` or `Greek`. Names are matched
+/// ignoring case, spaces, `_` and `-`.
+pub fn unicode_property(name: &str) -> Option<CharClass> {
+    let name: String = name.chars()
+        .filter(|&c| c != ' ' && c != '_' && c != '-')
+        .flat_map(char::to_lowercase)
+        .collect();
+    let properties = unicode_tables::PROPERTIES;
+    let i = properties.binary_search_by_key(&&*name, |&(name, _)| name).ok()?;
+    let ranges = properties[i].1.iter()
+        .flat_map(|table| table.iter().cloned())
+        .collect();
+    Some(CharClass::new(ranges))
+}
+
+/// Returns the chars in the POSIX class written `[:name:]`, such as `alpha`
+/// or `xdigit`. These are always ASCII-only, as in the C locale.
+pub fn posix_class(name: &str) -> Option<CharClass> {
+    let ranges: &[(char, char)] = match name {
+        "alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        "alpha" => &[('A', 'Z'), ('a', 'z')],
+        "blank" => &[('\t', '\t'), (' ', ' ')],
+        "cntrl" => &[('\0', '\x1F'), ('\x7F', '\x7F')],
+        "digit" => &[('0', '9')],
+        "graph" => &[('!', '~')],
+        "lower" => &[('a', 'z')],
+        "print" => &[(' ', '~')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        "space" => &[('\t', '\r'), (' ', ' ')],
+        "upper" => &[('A', 'Z')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        _ => return None
+    };
+    Some(CharClass { ranges: ranges.to_vec() })
+}
+
+/// Returns the chars in the equivalence class written `[=c=]`: the letter
+/// `c` is made of, if it is a letter with combining marks such as `é`, and
+/// every char made of that letter and combining marks. Any other char is
+/// only equivalent to itself.
+pub fn equivalence_class(c: char) -> CharClass {
+    let class = unicode_tables::EQUIVALENCE_CLASSES.iter()
+        .find(|&&(_, table)| ranges_contain(table, c));
+    match class {
+        Some(&(_, table)) => CharClass { ranges: table.to_vec() },
+        None => CharClass::new(vec![(c, c)])
+    }
+}
+
+/// Returns true if `a` and `b` are equal when case is ignored, as for
+/// `CharClass::case_fold`.
+pub fn eq_ignoring_case(a: char, b: char, unicode: bool) -> bool {
+    if a == b {
+        return true;
+    }
+    if !unicode {
+        return a.eq_ignore_ascii_case(&b);
+    }
+    let table = unicode_tables::CASE_FOLDING;
+    match table.binary_search_by_key(&a, |&(c, _)| c) {
+        Ok(i) => table[i].1.contains(&b),
+        Err(_) => false
+    }
+}
+
+/// Returns true if `c` is in one of `ranges`, which must be sorted and
+/// non-overlapping, by binary search.
+fn ranges_contain(ranges: &[(char, char)], c: char) -> bool {
+    let mut low = 0;
+    let mut high = ranges.len();
+    while low < high {
+        let mid = (low + high) / 2;
+        let (start, end) = ranges[mid];
+        if c < start {
+            high = mid;
+        } else if c > end {
+            low = mid + 1;
+        } else {
+            return true;
+        }
+    }
+    false
+}
+
+/// The char after `c`, skipping the surrogate gap.
+fn next_char(c: char) -> Option<char> {
+    match c {
+        '\u{D7FF}' => Some('\u{E000}'),
+        char::MAX => None,
+        _ => char::from_u32(c as u32 + 1)
+    }
+}
+
+/// The char before `c`, skipping the surrogate gap.
+fn prev_char(c: char) -> Option<char> {
+    match c {
+        '\u{E000}' => Some('\u{D7FF}'),
+        '\0' => None,
+        _ => char::from_u32(c as u32 - 1)
+    }
+}
@@ -1,24 +1,172 @@
 use std::fmt;
 use std::result::Result;
 
+use class::{self, CharClass, PerlClass};
+use thompson_nfa;
+
 #[derive(Debug, PartialEq)]
 pub enum Regexp {
+    /// The empty string, as in an empty pattern, group or alternative such
+    /// as `()` or `a|`. Always matches, without consuming any input.
+    Empty,
     Char(char),
     Concatenation(Vec<Regexp>),
     Alternation(Vec<Regexp>),
-    Optional(Box<Regexp>),
-    Repeated(Box<Regexp>),
-    OptionalRepeated(Box<Regexp>),
+    Optional(Box<Regexp>, Greed),
+    Repeated(Box<Regexp>, Greed),
+    OptionalRepeated(Box<Regexp>, Greed),
+    /// A parenthesized group whose match is recorded as capture group
+    /// `index`. Groups are numbered from 1 in order of their opening
+    /// parenthesis, named or not; group 0 is the whole match. `name` is set
+    /// for groups written `(?P<name>...)` or `(?<name>...)`.
+    Capture { index: usize, name: Option<String>, inner: Box<Regexp> },
+    /// A bracketed class such as `[a-z_]`, matching any one char in `class`,
+    /// or any one char not in it if `negated`.
+    Class { class: CharClass, negated: bool },
+    /// One of the predefined classes `\d`, `\s` and `\w`, or their
+    /// negations `\D`, `\S` and `\W` if `negated`. Only ASCII chars are in
+    /// the class unless `unicode` is set, which is the case when the
+    /// pattern was parsed with `Flags::unicode`.
+    Perl { class: PerlClass, negated: bool, unicode: bool },
+    /// `\p{name}`, matching any one char with the Unicode general category
+    /// or script `name`, or `\P{name}`, matching any other char if
+    /// `negated`.
+    Property { name: String, negated: bool },
+    /// `.`, matching any one char. `\n` is only matched if `newline` is set,
+    /// which is the case when the pattern was parsed with
+    /// `Flags::dot_matches_new_line`.
+    AnyChar { newline: bool },
+    /// A zero-width assertion about the current position.
+    Assertion(Assertion),
+    /// `inner` repeated at least `min` times and at most `max` times, or
+    /// without an upper bound if `max` is `None`.
+    Repeat { inner: Box<Regexp>, min: u32, max: Option<u32>, greed: Greed },
+    /// `\n` or `\k<name>`, matching the text last matched by capture group
+    /// `n`, or failing if the group has not matched.
+    Backreference(usize),
+    /// `(?=inner)`, matching the empty string if `inner` matches starting
+    /// here, or `(?!inner)` if `negated`, matching it if `inner` does not.
+    Lookahead { inner: Box<Regexp>, negated: bool },
+    /// `(?<=inner)`, matching the empty string if `inner` matches ending
+    /// here, or `(?<!inner)` if `negated`, matching it if `inner` does not.
+    /// `inner` must match a bounded number of chars.
+    Lookbehind { inner: Box<Regexp>, negated: bool },
+    /// `(?>inner)`: once `inner` has matched, the rest of the pattern never
+    /// backtracks into it to try another way of matching it.
+    Atomic(Box<Regexp>),
+    /// A char, class, property or backreference parsed with
+    /// `Flags::case_insensitive`, matching regardless of case by Unicode
+    /// simple case folding, or only between ASCII letters unless `unicode`
+    /// is set. Only wraps `inner` if that changes what it matches.
+    CaseInsensitive { inner: Box<Regexp>, unicode: bool },
+}
+
+/// Whether a quantifier prefers to match as many repetitions as it can or as
+/// few.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Greed {
+    Greedy,
+    /// Written with a trailing `?`, as in `a*?`.
+    Lazy,
+    /// Written with a trailing `+`, as in `a*+`. Matches as many
+    /// repetitions as it can and never gives any back, like an atomic group
+    /// around the greedy quantifier.
+    Possessive,
+}
+
+/// The zero-width assertions a `Regexp` can make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assertion {
+    /// `\A`, or `^` outside multi-line mode: the start of the input.
+    StartText,
+    /// `\z`, or `$` outside multi-line mode: the end of the input.
+    EndText,
+    /// `^` in multi-line mode: the start of the input or of a line.
+    StartLine,
+    /// `$` in multi-line mode: the end of the input or of a line.
+    EndLine,
+    /// `\b`: a word char on exactly one side. Only ASCII chars are word
+    /// chars unless `unicode` is set.
+    WordBoundary { unicode: bool },
+    /// `\B`: word chars on both sides or on neither.
+    NotWordBoundary { unicode: bool },
+}
+
+/// Options that change the meaning of parts of a pattern.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flags {
+    /// `.` also matches `\n`.
+    pub dot_matches_new_line: bool,
+    /// `^` and `$` also match at the start and end of each line.
+    pub multi_line: bool,
+    /// Letters match regardless of case. Set by the `i` flag.
+    pub case_insensitive: bool,
+    /// Whitespace and `#` comments in the pattern are ignored. Set by the
+    /// `x` flag.
+    pub ignore_whitespace: bool,
+    /// Predefined classes such as `\d` and word boundaries cover all of
+    /// Unicode rather than only ASCII. Set by the `u` flag, and on by
+    /// default.
+    pub unicode: bool,
+}
+
+impl Default for Flags {
+    fn default() -> Flags {
+        Flags { dot_matches_new_line: false,
+                multi_line: false,
+                case_insensitive: false,
+                ignore_whitespace: false,
+                unicode: true }
+    }
 }
 
 
 #[derive(Debug, PartialEq)]
 pub enum RegexpError {
-    EmptyRegexp,
-    EmptyGroup(usize),
-    EmptyAlternative(usize),
     MisplacedOperator(usize),
     UnmatchedParenthesis(usize),
+    /// A `[` with no closing `]`.
+    UnterminatedClass(usize),
+    /// A class range such as `z-a` whose start is after its end.
+    ReversedRange(usize),
+    /// A `{` that does not start a well-formed `{n}`, `{n,}` or `{n,m}`.
+    InvalidRepetition(usize),
+    /// A counted repetition such as `{3,2}` whose minimum is above its
+    /// maximum.
+    ReversedRepetition(usize),
+    /// A counted repetition with a count above `MAX_REPETITION`.
+    RepetitionTooLarge(usize),
+    /// A `(?` that does not start any known kind of group.
+    UnknownGroup(usize),
+    /// A letter in a `(?flags)` or `(?flags:...)` group that is not one of
+    /// the flags `i`, `m`, `s`, `u` and `x`, or a second `-`.
+    UnknownFlag(usize),
+    /// A backslash followed by nothing, or by a char that has no escape.
+    UnknownEscape(usize),
+    /// A `\x` or `\u` escape that is not well-formed or does not name a
+    /// valid char.
+    InvalidHexEscape(usize),
+    /// A `\p` or `\P` escape that is not well-formed or does not name a
+    /// known Unicode general category or script.
+    UnknownProperty(usize),
+    /// A `[:name:]` inside a class whose name is not one of the POSIX
+    /// classes.
+    UnknownClassName(usize),
+    /// A capture group name that is empty, is not made of ASCII letters,
+    /// digits and `_`, starts with a digit, or is missing its closing `>`.
+    InvalidGroupName(usize),
+    /// A capture group name already used by an earlier group.
+    DuplicateGroupName(usize),
+    /// A `\n` or `\k<name>` backreference to a group that is not opened
+    /// before it.
+    InvalidBackreference(usize),
+    /// A lookbehind such as `(?<=a+)` that can match any number of chars.
+    /// Lookbehinds must have an upper bound on their length.
+    UnboundedLookbehind(usize),
+    /// A pattern whose compiled program would cost more than
+    /// `thompson_nfa::MAX_PROGRAM_COST`, usually because of nested counted
+    /// repetitions such as `((a{100}){100}){100}`.
+    PatternTooLarge,
 }
 
 impl fmt::Display for RegexpError {
@@ -29,130 +177,889 @@ impl fmt::Display for RegexpError {
 
 impl Regexp {
     pub fn from_string(string: &str) -> Result<Regexp, RegexpError> {
-        let mut stack = Vec::new();
-        let mut escaped = false;
-        let mut depth = 0;
-        let mut group = String::new();
-        let mut num_alternatives = 0;
+        Regexp::from_string_with_flags(string, Flags::default())
+    }
 
+    /// The fewest and most chars this regexp can match, or `None` for the
+    /// most if there is no limit.
+    pub fn length_bounds(&self) -> (usize, Option<usize>) {
         use self::Regexp::*;
-        use self::RegexpError::*;
+        match *self {
+            Char(_) | Class { .. } | Perl { .. } | Property { .. }
+                | AnyChar { .. } => (1, Some(1)),
+            Empty | Assertion(_) | Lookahead { .. } | Lookbehind { .. }
+                => (0, Some(0)),
+            Backreference(_) => (0, None),
+            Concatenation(ref regexps) => regexps.iter()
+                .map(Regexp::length_bounds)
+                .fold((0, Some(0)), |(min, max), (sub_min, sub_max)| {
+                    (min.saturating_add(sub_min),
+                     max.and_then(|max| max.checked_add(sub_max?)))
+                }),
+            Alternation(ref regexps) => regexps.iter()
+                .map(Regexp::length_bounds)
+                .fold((usize::MAX, Some(0)), |(min, max), (sub_min, sub_max)| {
+                    (min.min(sub_min),
+                     max.and_then(|max| Some(max.max(sub_max?))))
+                }),
+            Capture { ref inner, .. } | Atomic(ref inner)
+                | CaseInsensitive { ref inner, .. } => inner.length_bounds(),
+            Optional(ref inner, _) => (0, inner.length_bounds().1),
+            Repeated(ref inner, _) | OptionalRepeated(ref inner, _) => {
+                let (min, max) = inner.length_bounds();
+                let min = if let Repeated(..) = *self { min } else { 0 };
+                (min, if max == Some(0) { max } else { None })
+            },
+            Repeat { ref inner, min: min_count, max: max_count, .. } => {
+                let (min, max) = inner.length_bounds();
+                let max = match (max, max_count) {
+                    (Some(0), _) => Some(0),
+                    (Some(max), Some(count)) => max.checked_mul(count as usize),
+                    _ => None
+                };
+                (min.saturating_mul(min_count as usize), max)
+            }
+        }
+    }
 
-        let mut open_paren_index_stack = Vec::new();
+    /// The number of capture groups, including group 0 for the whole match.
+    /// Groups that compile to nothing, such as the one in `(a){0}`, still
+    /// count.
+    pub fn captures_len(&self) -> usize {
+        use self::Regexp::*;
+        match *self {
+            Empty | Char(_) | Class { .. } | Perl { .. } | Property { .. }
+                | AnyChar { .. } | Assertion(_) | Backreference(_) => 1,
+            Concatenation(ref regexps) | Alternation(ref regexps) => regexps
+                .iter()
+                .map(Regexp::captures_len)
+                .fold(1, usize::max),
+            Capture { index, ref inner, .. }
+                => (index + 1).max(inner.captures_len()),
+            Optional(ref inner, _) | Repeated(ref inner, _)
+                | OptionalRepeated(ref inner, _) | Repeat { ref inner, .. }
+                | Atomic(ref inner) | Lookahead { ref inner, .. }
+                | Lookbehind { ref inner, .. }
+                | CaseInsensitive { ref inner, .. } => inner.captures_len()
+        }
+    }
 
-        for (i, c) in string.chars().enumerate() {
-            if escaped {
-                match c {
-                    '('|')'|'?'|'+'|'*'|'\\' => stack.push(Char('\\')),
-                    _ => ()
+    /// Parses `string` with `flags` in effect.
+    pub fn from_string_with_flags(string: &str, flags: Flags)
+                                  -> Result<Regexp, RegexpError> {
+        let mut parser = Parser {
+            chars: string.chars().collect(),
+            pos: 0,
+            flags,
+            num_captures: 0,
+            capture_names: Vec::new(),
+            deferred_error: None
+        };
+        let regexp = parser.parse_alternation(None)?;
+        if let Some(err) = parser.deferred_error {
+            return Result::Err(err);
+        }
+        // Nested repetitions multiply, so the compiled program can be huge
+        // even though every count is small.
+        match thompson_nfa::program_cost(&regexp) {
+            Some(cost) if cost <= thompson_nfa::MAX_PROGRAM_COST
+                => Result::Ok(regexp),
+            _ => Result::Err(RegexpError::PatternTooLarge)
+        }
+    }
+}
+
+/// The largest count allowed in a counted repetition such as `{n,m}`. Each
+/// repetition is compiled to its own copy of the repeated program.
+pub const MAX_REPETITION: u32 = 1000;
+
+/// A parsed quantifier, before it is applied to the preceding regexp.
+enum Quantifier {
+    Optional,
+    Repeated,
+    OptionalRepeated,
+    Repeat(u32, Option<u32>),
+}
+
+/// Recursive descent parser over the chars of a regexp string. All error
+/// positions are char indices into the string.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    flags: Flags,
+    num_captures: usize,
+    /// The name and index of every named group opened so far.
+    capture_names: Vec<(String, usize)>,
+    /// The first error found inside a group. Unbalanced parentheses are
+    /// reported in preference to these, so parsing continues past them.
+    deferred_error: Option<RegexpError>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn defer(&mut self, err: RegexpError) {
+        if self.deferred_error.is_none() {
+            self.deferred_error = Some(err);
+        }
+    }
+
+    /// Parses alternatives separated by `|` up to the end of the enclosing
+    /// group, which was opened at `open_paren`, or the end of the string.
+    /// The closing parenthesis is left for the caller.
+    fn parse_alternation(&mut self, open_paren: Option<usize>)
+                         -> Result<Regexp, RegexpError> {
+        use self::RegexpError::*;
+
+        let mut alternatives = Vec::new();
+        loop {
+            alternatives.push(self.parse_concatenation()?);
+            match self.peek() {
+                Some('|') => self.pos += 1,
+                end => {
+                    if end.is_none() {
+                        if let Some(i) = open_paren {
+                            return Result::Err(UnmatchedParenthesis(i));
+                        }
+                    } else if open_paren.is_none() {
+                        return Result::Err(UnmatchedParenthesis(self.pos));
+                    }
+                    break;
                 }
-                stack.push(Char(c));
-                escaped = false;
-                continue;
             }
+        }
+
+        match alternatives.len() {
+            1 => Result::Ok(alternatives.pop().unwrap()),
+            _ => Result::Ok(Regexp::Alternation(alternatives))
+        }
+    }
+
+    /// Skips any `(?#...)` comments under the cursor, as well as whitespace
+    /// and `#` comments running to the end of the line if
+    /// `Flags::ignore_whitespace` is set.
+    fn skip_comments(&mut self) -> Result<(), RegexpError> {
+        loop {
+            match self.peek() {
+                Some('(') if self.chars.get(self.pos + 1) == Some(&'?')
+                    && self.chars.get(self.pos + 2) == Some(&'#') => {
+                    let open_paren = self.pos;
+                    while self.peek().is_some_and(|c| c != ')') {
+                        self.pos += 1;
+                    }
+                    if self.peek().is_none() {
+                        return Result::Err(
+                            RegexpError::UnmatchedParenthesis(open_paren));
+                    }
+                },
+                Some('#') if self.flags.ignore_whitespace => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                },
+                Some(c) if c.is_whitespace() && self.flags.ignore_whitespace
+                    => (),
+                _ => return Result::Ok(())
+            }
+            self.pos += 1;
+        }
+    }
 
+    /// Parses a sequence of quantified atoms up to the next `|`, `)` or the
+    /// end of the string, which may be empty.
+    fn parse_concatenation(&mut self) -> Result<Regexp, RegexpError> {
+        use self::Regexp::*;
+        use self::RegexpError::*;
+
+        let mut stack = Vec::new();
+        // Whether the last thing parsed was a `(?flags)` group, which leaves
+        // nothing on the stack for a quantifier to apply to.
+        let mut after_flags = false;
+        loop {
+            self.skip_comments()?;
+            let c = match self.peek() {
+                Some(c) => c,
+                None => break
+            };
+            let i = self.pos;
+            let was_after_flags = after_flags;
+            after_flags = false;
             match c {
-                '(' => { if depth > 0 { group.push('(') };
-                         depth += 1;
-                         open_paren_index_stack.push(i);
-                         continue; },
-                ')' => {
-                    depth -= 1;
-                    if depth < 0 {
-                        return Result::Err(UnmatchedParenthesis(i));
-                    } else if depth == 0 {
-                        // println!("group: {}", group);
-                        let group_regexp = match Regexp::from_string(&group) {
-                            Ok(value) => value,
-                            Err(err) => return Result::Err(match err {
-                                EmptyRegexp => EmptyGroup(i-1),
-                                EmptyGroup(inner_i)
-                                    => EmptyGroup(inner_i + i - group.len()),
-                                EmptyAlternative(inner_i)
-                                    => EmptyAlternative(inner_i + i - group.len()),
-                                MisplacedOperator(inner_i)
-                                    => MisplacedOperator(inner_i + i - group.len()),
-                                UnmatchedParenthesis(inner_i)
-                                    => UnmatchedParenthesis(inner_i + i - group.len())
-                            })
-                        };
-                        stack.push(group_regexp);
-                        group = String::new();
-                    } else {
-                        group.push(')');
+                '|' | ')' => break,
+                '(' => match self.parse_group()? {
+                    Some(group) => stack.push(group),
+                    None => after_flags = true
+                },
+                '[' => {
+                    let class = self.parse_class()?;
+                    stack.push(self.fold_case(class));
+                },
+                '.' => {
+                    stack.push(AnyChar {
+                        newline: self.flags.dot_matches_new_line
+                    });
+                    self.pos += 1;
+                },
+                '^' | '$' => {
+                    stack.push(Assertion(match (c, self.flags.multi_line) {
+                        ('^', false) => self::Assertion::StartText,
+                        ('^', true) => self::Assertion::StartLine,
+                        ('$', false) => self::Assertion::EndText,
+                        _ => self::Assertion::EndLine
+                    }));
+                    self.pos += 1;
+                },
+                '\\' if self.chars.get(i + 1) == Some(&'Q') => {
+                    let chars = self.parse_quote();
+                    stack.extend(chars.into_iter()
+                                     .map(|c| self.fold_case(Char(c))));
+                },
+                '\\' => {
+                    let escape = self.parse_escape()?;
+                    stack.push(self.fold_case(escape));
+                },
+                '?' | '+' | '*' | '{' => {
+                    let quantifier = self.parse_quantifier()?;
+                    let greed = match self.peek() {
+                        Some('?') => Greed::Lazy,
+                        Some('+') => Greed::Possessive,
+                        _ => Greed::Greedy
+                    };
+                    if greed != Greed::Greedy { self.pos += 1; }
+                    if was_after_flags {
+                        self.defer(MisplacedOperator(i));
+                        continue;
                     }
-                    open_paren_index_stack.pop();
-                    continue;
+                    match stack.pop() {
+                        Some(prev_regexp) => {
+                            let prev_regexp = Box::new(prev_regexp);
+                            stack.push(match quantifier {
+                                Quantifier::Optional
+                                    => Optional(prev_regexp, greed),
+                                Quantifier::Repeated
+                                    => Repeated(prev_regexp, greed),
+                                Quantifier::OptionalRepeated
+                                    => OptionalRepeated(prev_regexp, greed),
+                                Quantifier::Repeat(min, max) => Repeat {
+                                    inner: prev_regexp, min, max, greed
+                                }
+                            });
+                        },
+                        None => self.defer(MisplacedOperator(i))
+                    }
+                },
+                _ => {
+                    stack.push(self.fold_case(Char(c)));
+                    self.pos += 1;
                 }
-                _ => ()
             }
-            if depth != 0 {
-                group.push(c);
-                continue;
+        }
+
+        match stack.len() {
+            0 => Result::Ok(Empty),
+            1 => Result::Ok(stack.pop().unwrap()),
+            _ => Result::Ok(Concatenation(stack))
+        }
+    }
+
+    /// Parses a group starting at the `(` under the cursor, up to and
+    /// including its `)`. Groups starting with `(?` are atomic `(?>...)`,
+    /// lookarounds `(?=...)`, `(?!...)`, `(?<=...)` and `(?<!...)`, named
+    /// `(?P<name>...)` and `(?<name>...)`, or non-capturing `(?flags:...)`
+    /// with flags such as `i` or `-s` switched for the group; all other
+    /// groups are numbered captures. A bare `(?flags)` switches flags for
+    /// the rest of the enclosing group and returns `None`.
+    fn parse_group(&mut self) -> Result<Option<Regexp>, RegexpError> {
+        enum Kind {
+            Capture(Option<String>),
+            NonCapturing,
+            Atomic,
+            Lookahead(bool),
+            Lookbehind(bool)
+        }
+
+        let open_paren = self.pos;
+        let outer_flags = self.flags;
+        self.pos += 1;
+        let kind = if self.peek() == Some('?') {
+            self.pos += 1;
+            match self.peek() {
+                Some('>') => {
+                    self.pos += 1;
+                    Kind::Atomic
+                },
+                Some(c @ '=') | Some(c @ '!') => {
+                    self.pos += 1;
+                    Kind::Lookahead(c == '!')
+                },
+                Some('<') if self.chars.get(self.pos + 1) == Some(&'=')
+                    || self.chars.get(self.pos + 1) == Some(&'!') => {
+                    self.pos += 2;
+                    Kind::Lookbehind(self.chars[self.pos - 1] == '!')
+                },
+                Some('P') if self.chars.get(self.pos + 1) == Some(&'<') => {
+                    self.pos += 2;
+                    Kind::Capture(Some(self.parse_group_name()?))
+                },
+                Some('<') => {
+                    self.pos += 1;
+                    Kind::Capture(Some(self.parse_group_name()?))
+                },
+                _ => {
+                    self.flags = self.parse_flags(open_paren)?;
+                    if self.peek() == Some(')') {
+                        self.pos += 1;
+                        return Result::Ok(None);
+                    }
+                    self.pos += 1;
+                    Kind::NonCapturing
+                }
             }
-            match c {
-                '|' => {
-                    let mut alternative_stack = Vec::new();
-                    while stack.len() > num_alternatives {
-                        alternative_stack.push(stack.pop().unwrap());
+        } else {
+            Kind::Capture(None)
+        };
+        let index = match kind {
+            Kind::Capture(_) => {
+                self.num_captures += 1;
+                self.num_captures
+            },
+            _ => 0
+        };
+
+        let inner = self.parse_alternation(Some(open_paren))?;
+        self.pos += 1;
+        self.flags = outer_flags;
+
+        if let Kind::Lookbehind(_) = kind {
+            if inner.length_bounds().1.is_none() {
+                self.defer(RegexpError::UnboundedLookbehind(open_paren));
+            }
+        }
+
+        Result::Ok(Some(match kind {
+            Kind::Capture(name) => Regexp::Capture {
+                index, name, inner: Box::new(inner)
+            },
+            Kind::NonCapturing => inner,
+            Kind::Atomic => Regexp::Atomic(Box::new(inner)),
+            Kind::Lookahead(negated) => Regexp::Lookahead {
+                inner: Box::new(inner), negated
+            },
+            Kind::Lookbehind(negated) => Regexp::Lookbehind {
+                inner: Box::new(inner), negated
+            }
+        }))
+    }
+
+    /// Parses the flags after a `(?`, such as `im-s`, up to but not
+    /// including the `:` or `)` that ends them, and returns the current
+    /// flags updated by them.
+    fn parse_flags(&mut self, open_paren: usize) -> Result<Flags, RegexpError> {
+        use self::RegexpError::*;
+
+        let mut flags = self.flags;
+        let mut enable = true;
+        let first = self.pos;
+        loop {
+            let value = match self.peek() {
+                None => return Result::Err(UnmatchedParenthesis(open_paren)),
+                Some(')') if self.pos == first
+                    => return Result::Err(UnknownGroup(open_paren)),
+                Some(':') | Some(')') => return Result::Ok(flags),
+                Some('-') if enable => {
+                    enable = false;
+                    self.pos += 1;
+                    continue;
+                },
+                Some('i') => &mut flags.case_insensitive,
+                Some('m') => &mut flags.multi_line,
+                Some('s') => &mut flags.dot_matches_new_line,
+                Some('u') => &mut flags.unicode,
+                Some('x') => &mut flags.ignore_whitespace,
+                Some(c) if c.is_alphabetic() || c == '-'
+                    => return Result::Err(UnknownFlag(self.pos)),
+                Some(_) => return Result::Err(UnknownGroup(open_paren))
+            };
+            *value = enable;
+            self.pos += 1;
+        }
+    }
+
+    /// Parses a capture group name under the cursor, up to and including
+    /// its closing `>`.
+    fn parse_group_name(&mut self) -> Result<String, RegexpError> {
+        use self::RegexpError::*;
+
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '_') { break; }
+            self.pos += 1;
+        }
+        let name: String = self.chars[start..self.pos].iter().cloned()
+            .collect();
+        let valid = self.peek() == Some('>')
+            && name.chars().next().is_some_and(|c| !c.is_ascii_digit());
+        if !valid {
+            return Result::Err(InvalidGroupName(start));
+        }
+        if self.capture_names.iter().any(|(used, _)| *used == name) {
+            return Result::Err(DuplicateGroupName(start));
+        }
+        self.pos += 1;
+        self.capture_names.push((name.clone(), self.num_captures + 1));
+        Result::Ok(name)
+    }
+
+    /// Parses the quantifier under the cursor, not including a `?` or `+`
+    /// that makes it lazy or possessive.
+    fn parse_quantifier(&mut self) -> Result<Quantifier, RegexpError> {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+        match c {
+            '?' => Result::Ok(Quantifier::Optional),
+            '+' => Result::Ok(Quantifier::Repeated),
+            '*' => Result::Ok(Quantifier::OptionalRepeated),
+            _ => self.parse_repetition_bounds(self.pos - 1)
+        }
+    }
+
+    /// Parses the rest of a `{n}`, `{n,}` or `{n,m}` quantifier whose `{` is
+    /// at `open_brace`.
+    fn parse_repetition_bounds(&mut self, open_brace: usize)
+                               -> Result<Quantifier, RegexpError> {
+        use self::RegexpError::*;
+
+        let min = match self.parse_number() {
+            Some(min) => min,
+            None => return Result::Err(InvalidRepetition(open_brace))
+        };
+        let max = match self.peek() {
+            Some(',') => {
+                self.pos += 1;
+                match self.peek() {
+                    Some('}') => None,
+                    _ => match self.parse_number() {
+                        Some(max) => Some(max),
+                        None => return Result::Err(
+                            InvalidRepetition(open_brace))
                     }
-                    alternative_stack.reverse();
-                    match alternative_stack.len() {
-                        0 => return Result::Err(EmptyAlternative(i)),
-                        1 => stack.push(alternative_stack.pop().unwrap()),
-                        _ => stack.push(Concatenation(alternative_stack))
+                }
+            },
+            _ => Some(min)
+        };
+        if self.peek() != Some('}') {
+            return Result::Err(InvalidRepetition(open_brace));
+        }
+        self.pos += 1;
+        if max.is_some_and(|max| max < min) {
+            return Result::Err(ReversedRepetition(open_brace));
+        }
+        if max.unwrap_or(min) > MAX_REPETITION {
+            return Result::Err(RepetitionTooLarge(open_brace));
+        }
+        Result::Ok(Quantifier::Repeat(min, max))
+    }
+
+    /// Parses a decimal number, returning `None` if there are no digits or
+    /// the number does not fit in a `u32`.
+    fn parse_number(&mut self) -> Option<u32> {
+        let mut number: Option<u32> = None;
+        while let Some(digit) = self.peek().and_then(|c| c.to_digit(10)) {
+            number = Some(number.unwrap_or(0).checked_mul(10)?
+                          .checked_add(digit)?);
+            self.pos += 1;
+        }
+        number
+    }
+
+    /// Parses an escape sequence starting at the `\\` under the cursor: one
+    /// of the assertions `\A`, `\z`, `\b` and `\B`, a backreference, a
+    /// predefined class, a Unicode property or an escaped char.
+    fn parse_escape(&mut self) -> Result<Regexp, RegexpError> {
+        if let Some(index) = self.parse_backreference()? {
+            return Result::Ok(Regexp::Backreference(index));
+        }
+        if let Some((name, negated)) = self.parse_property()? {
+            return Result::Ok(Regexp::Property { name, negated });
+        }
+        if let Some((class, negated)) = self.parse_perl_class() {
+            return Result::Ok(Regexp::Perl {
+                class, negated, unicode: self.flags.unicode
+            });
+        }
+        let assertion = match self.chars.get(self.pos + 1) {
+            Some(&'A') => self::Assertion::StartText,
+            Some(&'z') => self::Assertion::EndText,
+            Some(&'b') => self::Assertion::WordBoundary {
+                unicode: self.flags.unicode
+            },
+            Some(&'B') => self::Assertion::NotWordBoundary {
+                unicode: self.flags.unicode
+            },
+            _ => return self.parse_escaped_char().map(Regexp::Char)
+        };
+        self.pos += 2;
+        Result::Ok(Regexp::Assertion(assertion))
+    }
+
+    /// Parses a backreference `\n` or `\k<name>` if there is one starting at
+    /// the `\\` under the cursor, returning the index of the group it
+    /// refers to. All the digits after the backslash make up `n`.
+    fn parse_backreference(&mut self) -> Result<Option<usize>, RegexpError> {
+        let backslash = self.pos;
+        let index = match self.chars.get(self.pos + 1) {
+            Some(&('1'..='9')) => {
+                self.pos += 1;
+                self.parse_number().map(|n| n as usize)
+            },
+            Some(&'k') if self.chars.get(self.pos + 2) == Some(&'<') => {
+                self.pos += 3;
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c != '>') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter()
+                    .cloned().collect();
+                self.pos += 1;
+                self.capture_names.iter()
+                    .find(|&(used, _)| *used == name)
+                    .map(|&(_, index)| index)
+            },
+            _ => return Result::Ok(None)
+        };
+        match index {
+            Some(index) if index <= self.num_captures
+                && self.pos <= self.chars.len() => Result::Ok(Some(index)),
+            _ => Result::Err(RegexpError::InvalidBackreference(backslash))
+        }
+    }
+
+    /// Parses a predefined class such as `\d` or `\W` if there is one
+    /// starting at the `\\` under the cursor, returning the class and
+    /// whether it is negated.
+    fn parse_perl_class(&mut self) -> Option<(PerlClass, bool)> {
+        let class = match self.chars.get(self.pos + 1) {
+            Some(&'d') => (PerlClass::Digit, false),
+            Some(&'D') => (PerlClass::Digit, true),
+            Some(&'s') => (PerlClass::Space, false),
+            Some(&'S') => (PerlClass::Space, true),
+            Some(&'w') => (PerlClass::Word, false),
+            Some(&'W') => (PerlClass::Word, true),
+            _ => return None
+        };
+        self.pos += 2;
+        Some(class)
+    }
+
+    /// Parses a Unicode property class `\p{name}`, `\P{name}` or, for a
+    /// one-letter name, `\pL` if there is one starting at the `\\` under the
+    /// cursor, returning its name and whether it is negated.
+    fn parse_property(&mut self)
+                      -> Result<Option<(String, bool)>, RegexpError> {
+        let backslash = self.pos;
+        let negated = match self.chars.get(self.pos + 1) {
+            Some(&'p') => false,
+            Some(&'P') => true,
+            _ => return Result::Ok(None)
+        };
+        self.pos += 2;
+        let name: String = match self.peek() {
+            Some('{') => {
+                let start = self.pos + 1;
+                match self.chars[start..].iter().position(|&c| c == '}') {
+                    Some(len) => {
+                        self.pos = start + len + 1;
+                        self.chars[start..start + len].iter().cloned()
+                            .collect()
+                    },
+                    None => {
+                        return Result::Err(
+                            RegexpError::UnknownProperty(backslash));
                     }
-                    num_alternatives += 1;
                 }
-                '?' | '+' | '*' => {
-                    if stack.len() == num_alternatives {
-                        return Result::Err(MisplacedOperator(i));
+            },
+            Some(c) => {
+                self.pos += 1;
+                c.to_string()
+            },
+            None => return Result::Err(RegexpError::UnknownProperty(backslash))
+        };
+        if class::unicode_property(&name).is_none() {
+            return Result::Err(RegexpError::UnknownProperty(backslash));
+        }
+        Result::Ok(Some((name, negated)))
+    }
+
+    /// Parses an escape sequence for a single char starting at the `\\`
+    /// under the cursor. These are the control chars `\n`, `\t`, `\r`, `\f`,
+    /// `\v`, `\a`, `\e` and `\0`, the code points `\xHH`, `\x{H...}` and
+    /// `\u{H...}`, and any ASCII punctuation or space escaped to stand for
+    /// itself.
+    fn parse_escaped_char(&mut self) -> Result<char, RegexpError> {
+        use self::RegexpError::*;
+
+        let backslash = self.pos;
+        self.pos += 1;
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Result::Err(UnknownEscape(backslash))
+        };
+        self.pos += 1;
+        Result::Ok(match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'f' => '\u{C}',
+            'v' => '\u{B}',
+            'a' => '\u{7}',
+            'e' => '\u{1B}',
+            '0' => '\0',
+            'x' | 'u' => {
+                let digits = if self.peek() == Some('{') {
+                    self.pos += 1;
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                        self.pos += 1;
                     }
-                    let prev_regexp = Box::new(match stack.pop() {
-                        Some(value) => value,
-                        None => return Result::Err(MisplacedOperator(i))
-                    });
-                    stack.push(match c {
-                        '?' => Optional(prev_regexp),
-                        '+' => Repeated(prev_regexp),
-                        '*' => OptionalRepeated(prev_regexp),
-                        _ => unreachable!()
-                    });
+                    if self.peek() != Some('}') {
+                        return Result::Err(InvalidHexEscape(backslash));
+                    }
+                    self.pos += 1;
+                    start..self.pos - 1
+                } else if c == 'x' {
+                    self.pos += 2;
+                    backslash + 2..self.pos
+                } else {
+                    return Result::Err(InvalidHexEscape(backslash));
+                };
+                let hex: String = match self.chars.get(digits) {
+                    Some(hex) => hex.iter().cloned().collect(),
+                    None => return Result::Err(InvalidHexEscape(backslash))
+                };
+                match u32::from_str_radix(&hex, 16).ok()
+                    .and_then(::std::char::from_u32) {
+                    Some(c) if hex.len() <= 8
+                        && hex.chars().all(|c| c.is_ascii_hexdigit()) => c,
+                    _ => return Result::Err(InvalidHexEscape(backslash))
                 }
-                _ => stack.push(Char(c))
-            }
+            },
+            c if c.is_ascii_punctuation() || c == ' ' => c,
+            _ => return Result::Err(UnknownEscape(backslash))
+        })
+    }
+
+    /// Wraps `regexp` in a `Regexp::CaseInsensitive` if the `i` flag is set
+    /// and `regexp` is a char, class, property or backreference that would
+    /// then match more chars.
+    fn fold_case(&self, regexp: Regexp) -> Regexp {
+        if !self.flags.case_insensitive {
+            return regexp;
         }
+        let unicode = self.flags.unicode;
+        let class = match regexp {
+            Regexp::Char(c) => CharClass::new(vec![(c, c)]),
+            Regexp::Class { ref class, .. } => class.clone(),
+            Regexp::Property { ref name, .. }
+                => class::unicode_property(name).unwrap(),
+            Regexp::Backreference(_) => CharClass::empty(),
+            _ => return regexp
+        };
+        let folds = match regexp {
+            Regexp::Backreference(_) => true,
+            _ => class.case_fold(unicode) != class
+        };
+        if !folds {
+            return regexp;
+        }
+        Regexp::CaseInsensitive { inner: Box::new(regexp), unicode }
+    }
 
-        if depth > 0 {
-            return Result::Err(
-                UnmatchedParenthesis(open_paren_index_stack.pop().unwrap()));
+    /// Parses a quote starting at the `\Q` under the cursor, up to and
+    /// including the `\E` that ends it or to the end of the pattern, and
+    /// returns the chars in between. These are all literal, even in verbose
+    /// mode.
+    fn parse_quote(&mut self) -> Vec<char> {
+        let start = self.pos + 2;
+        let len = self.chars[start..].windows(2)
+            .position(|pair| pair == ['\\', 'E'])
+            .unwrap_or(self.chars.len() - start);
+        self.pos = (start + len + 2).min(self.chars.len());
+        self.chars[start..start + len].to_vec()
+    }
+
+    /// Parses a bracketed class starting at the `[` under the cursor, up to
+    /// and including its closing `]`. A `]` first in the class and a `-`
+    /// first or last in the class or before an operator are literal, and
+    /// chars can be escaped as they are outside a class. Predefined classes
+    /// such as `\d` and Unicode properties such as `\p{L}` add all of their
+    /// chars, as do nested classes such as `[^a-z]`.
+    ///
+    /// The operators `&&`, `--` and `~~` take the intersection, difference
+    /// and symmetric difference of the chars before and after them. They
+    /// bind more loosely than listing chars side by side, and apply from
+    /// left to right. An operator with nothing before or after it stands
+    /// for its two chars, so `[--a]` is the range from `-` to `a` and
+    /// `[a&&]` holds `a` and `&`.
+    fn parse_class(&mut self) -> Result<Regexp, RegexpError> {
+        let (class, negated) = self.parse_class_set()?;
+        Result::Ok(Regexp::Class { class, negated })
+    }
+
+    /// Parses the class starting at the `[` under the cursor as for
+    /// `parse_class`, and returns its chars before any leading `^` is applied
+    /// together with whether it is negated.
+    fn parse_class_set(&mut self) -> Result<(CharClass, bool), RegexpError> {
+        let open_bracket = self.pos;
+        self.pos += 1;
+        let negated = self.peek() == Some('^');
+        if negated { self.pos += 1; }
+
+        let mut class = self.parse_class_operand(open_bracket, true)?;
+        while let Some(op) = self.class_operator(self.pos) {
+            self.pos += 2;
+            let other = self.parse_class_operand(open_bracket, false)?;
+            class = match op {
+                '&' => class.intersection(&other),
+                '-' => class.difference(&other),
+                _ => class.symmetric_difference(&other)
+            };
         }
+        self.pos += 1;
+
+        Result::Ok((class, negated))
+    }
 
-        if stack.len() == 0 {
-            return Result::Err(EmptyRegexp)
+    /// Returns the first char of the class operator `&&`, `--` or `~~` at
+    /// `pos`, if there is one.
+    fn class_operator(&self, pos: usize) -> Option<char> {
+        match (self.chars.get(pos), self.chars.get(pos + 1)) {
+            (Some(&c), Some(&next)) if c == next && "&-~".contains(c)
+                => Some(c),
+            _ => None
         }
+    }
 
-        if num_alternatives > 0 {
-            let mut alternative_stack = Vec::new();
-            while stack.len() > num_alternatives {
-                alternative_stack.push(stack.pop().unwrap());
+    /// Parses the chars listed side by side in the class opened at
+    /// `open_bracket`, up to the next class operator or the closing `]`,
+    /// which is left under the cursor. If the chars are `first` in the
+    /// class, a leading `]` is literal. An operator at the start of the
+    /// chars or just before the closing `]` is read as literal chars.
+    fn parse_class_operand(&mut self, open_bracket: usize, mut first: bool)
+                           -> Result<CharClass, RegexpError> {
+        use self::RegexpError::*;
+
+        let operand_start = self.pos;
+        let mut ranges = Vec::new();
+        loop {
+            if self.class_operator(self.pos).is_some()
+                && self.pos > operand_start
+                && self.chars.get(self.pos + 2) != Some(&']') {
+                break;
             }
-            alternative_stack.reverse();
-            match alternative_stack.len() {
-                0 => return Result::Err(EmptyAlternative(string.len()-1)),
-                1 => stack.push(alternative_stack.pop().unwrap()),
-                _ => stack.push(Concatenation(alternative_stack))
+            let start_pos = self.pos;
+            if self.peek() == Some('\\') {
+                let class = match self.parse_property()? {
+                    Some((name, negated)) => Some((
+                        class::unicode_property(&name).unwrap(), negated
+                    )),
+                    None => self.parse_perl_class().map(|(class, negated)| {
+                        (class.to_class(self.flags.unicode), negated)
+                    })
+                };
+                if let Some((class, negated)) = class {
+                    let class = if negated { class.negate() } else { class };
+                    ranges.extend_from_slice(class.ranges());
+                    first = false;
+                    continue;
+                }
+            }
+            if self.peek() == Some('[') {
+                let class = match self.parse_bracket_class()? {
+                    Some(class) => class,
+                    None => match self.parse_class_set()? {
+                        (class, true) => class.negate(),
+                        (class, false) => class
+                    }
+                };
+                ranges.extend_from_slice(class.ranges());
+                first = false;
+                continue;
             }
-            num_alternatives += 1;
+            let start = match self.peek() {
+                Some(']') if !first => break,
+                Some(_) => self.parse_class_char()?,
+                None => return Result::Err(UnterminatedClass(open_bracket))
+            };
+            first = false;
+
+            let is_range = self.peek() == Some('-')
+                && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']')
+                && self.class_operator(self.pos).is_none()
+                && self.class_operator(self.pos + 1).is_none();
+            if !is_range {
+                ranges.push((start, start));
+                continue;
+            }
+            self.pos += 1;
+            let end = self.parse_class_char()?;
+            if start > end {
+                return Result::Err(ReversedRange(start_pos));
+            }
+            ranges.push((start, end));
         }
 
-        match num_alternatives {
-            0 => match stack.len() {
-                1 => return Result::Ok(stack.pop().unwrap()),
-                _ => return Result::Ok(Concatenation(stack))
+        Result::Ok(CharClass::new(ranges))
+    }
+
+    /// Parses a POSIX class such as `[:alpha:]` or `[:^alpha:]`, or an
+    /// equivalence class such as `[=e=]`, at the `[` under the cursor inside
+    /// a class. Returns `None` and leaves the cursor alone if the `[` does
+    /// not start one, in which case it starts a nested class.
+    fn parse_bracket_class(&mut self)
+                           -> Result<Option<CharClass>, RegexpError> {
+        let open_bracket = self.pos;
+        let rest = &self.chars[self.pos..];
+        match rest.get(1) {
+            Some(&':') => {
+                let negated = rest.get(2) == Some(&'^');
+                let start = if negated { 3 } else { 2 };
+                let len = rest[start..].iter()
+                    .take_while(|c| c.is_ascii_alphabetic()).count();
+                if rest[start + len..].iter().take(2).ne([':', ']'].iter()) {
+                    return Result::Ok(None);
+                }
+                let name: String = rest[start..start + len].iter().collect();
+                let class = match class::posix_class(&name) {
+                    Some(class) => class,
+                    None => return Result::Err(
+                        RegexpError::UnknownClassName(open_bracket))
+                };
+                self.pos += start + len + 2;
+                Result::Ok(Some(if negated { class.negate() } else { class }))
+            },
+            Some(&'=') => match (rest.get(2), rest.get(3), rest.get(4)) {
+                (Some(&c), Some(&'='), Some(&']')) => {
+                    self.pos += 5;
+                    Result::Ok(Some(class::equivalence_class(c)))
+                },
+                _ => Result::Ok(None)
             },
-            1 => Result::Err(EmptyAlternative(string.len() - 1)),
-            _ => Result::Ok(Alternation(stack))
+            _ => Result::Ok(None)
+        }
+    }
+
+    /// Parses the char under the cursor inside a class, which may be
+    /// escaped.
+    fn parse_class_char(&mut self) -> Result<char, RegexpError> {
+        match self.peek() {
+            Some('\\') => self.parse_escaped_char(),
+            Some(c) => {
+                self.pos += 1;
+                Result::Ok(c)
+            },
+            None => unreachable!()
         }
     }
 }
@@ -461,33 +1368,396 @@ impl Regexp {
 // }
 
 pub fn regexp_to_string(regexp: &Regexp) -> String {
+    write_regexp(regexp)
+}
+
+/// Returns `regexp` as a verbose-mode pattern laid out over indented lines
+/// of at most `width` chars where possible, with one alternative per line
+/// and the contents of groups that do not fit on one line nested inside
+/// them. The pattern starts with `(?x)` and parses back to `regexp`.
+pub fn regexp_to_pretty_string(regexp: &Regexp, width: usize) -> String {
+    let mut lines = vec!["(?x)".to_string()];
+    layout_regexp(regexp, 0, width, &mut lines);
+    lines.join("\n")
+}
+
+/// Writes `regexp` on one line. Whitespace and `#` are always escaped, so
+/// the text means the same in verbose mode.
+fn write_regexp(regexp: &Regexp) -> String {
+    use self::Regexp::*;
+    match *regexp {
+        Empty => String::new(),
+        Char(c) => {
+            let mut text = String::new();
+            push_literal_char(&mut text, c);
+            text
+        },
+        Concatenation(ref regexps) => concatenation_pieces(regexps)
+            .into_iter()
+            .map(|(_, _, text)| text)
+            .collect::<Vec<String>>().join(""),
+        Alternation(ref regexps) => regexps.iter()
+            .map(|regexp| match *regexp {
+                Alternation(_)
+                    => format!("(?:{})", write_regexp(regexp)),
+                _ => write_regexp(regexp)
+            })
+            .collect::<Vec<String>>().join("|"),
+        Capture { ref inner, .. } | Atomic(ref inner)
+            | Lookahead { ref inner, .. } | Lookbehind { ref inner, .. }
+            | CaseInsensitive { ref inner, .. } => {
+                format!("{}{})", group_opener(regexp).unwrap(),
+                        write_regexp(inner))
+        },
+        Backreference(index) => format!("\\{}", index),
+        Class { ref class, negated } => class_to_string(class, negated),
+        Perl { class, negated, unicode } => {
+            let text = match (class, negated) {
+                (PerlClass::Digit, false) => "\\d",
+                (PerlClass::Digit, true) => "\\D",
+                (PerlClass::Space, false) => "\\s",
+                (PerlClass::Space, true) => "\\S",
+                (PerlClass::Word, false) => "\\w",
+                (PerlClass::Word, true) => "\\W"
+            };
+            if unicode {
+                text.to_string()
+            } else {
+                format!("(?-u:{})", text)
+            }
+        },
+        Property { ref name, negated } => {
+            format!("\\{}{{{}}}", if negated { 'P' } else { 'p' }, name)
+        },
+        AnyChar { newline: false } => ".".to_string(),
+        AnyChar { newline: true } => "(?s:.)".to_string(),
+        Assertion(assertion) => match assertion {
+            self::Assertion::StartText => "^",
+            self::Assertion::EndText => "$",
+            self::Assertion::StartLine => "(?m:^)",
+            self::Assertion::EndLine => "(?m:$)",
+            self::Assertion::WordBoundary { unicode: true } => "\\b",
+            self::Assertion::WordBoundary { unicode: false } => "(?-u:\\b)",
+            self::Assertion::NotWordBoundary { unicode: true } => "\\B",
+            self::Assertion::NotWordBoundary { unicode: false }
+                => "(?-u:\\B)"
+        }.to_string(),
+        Optional(ref inner_regexp, _)
+            | Repeated(ref inner_regexp, _)
+            | OptionalRepeated(ref inner_regexp, _)
+            | Repeat { inner: ref inner_regexp, .. } => {
+                let op_char = quantifier_op(regexp);
+                let text = write_regexp(inner_regexp);
+                if quantified_needs_group(inner_regexp, &op_char) {
+                    format!("(?:{}){}", text, op_char)
+                } else {
+                    format!("{}{}", text, op_char)
+                }
+        }
+    }
+}
+
+/// The opening of the group `regexp` is written as, up to its contents, or
+/// `None` if it is not a group.
+fn group_opener(regexp: &Regexp) -> Option<String> {
+    use self::Regexp::*;
+    Some(match *regexp {
+        Capture { name: None, .. } => "(".to_string(),
+        Capture { name: Some(ref name), .. } => format!("(?<{}>", name),
+        Atomic(_) => "(?>".to_string(),
+        Lookahead { negated, .. }
+            => format!("(?{}", if negated { '!' } else { '=' }),
+        Lookbehind { negated, .. }
+            => format!("(?<{}", if negated { '!' } else { '=' }),
+        CaseInsensitive { unicode: true, .. } => "(?i:".to_string(),
+        CaseInsensitive { unicode: false, .. } => "(?i-u:".to_string(),
+        _ => return None
+    })
+}
+
+/// Returns true if item `i` of a concatenation of `regexps` must be written
+/// inside a non-capturing group to print back to the same structure.
+fn concatenation_item_needs_group(regexps: &[Regexp], i: usize) -> bool {
     use self::Regexp::*;
+    match regexps[i] {
+        // Nested sequences and empty items only come from non-capturing
+        // groups, so they keep their group.
+        Alternation(_) | Concatenation(_) | Empty => true,
+        // A digit straight after `\n` would be read as part of `n`.
+        Backreference(_) => regexps.get(i + 1).is_some_and(|next| {
+            match *next {
+                Char(c) => c.is_ascii_digit(),
+                _ => false
+            }
+        }),
+        _ => false
+    }
+}
+
+/// Writes the items of a concatenation of `regexps` as `write_regexp` does,
+/// each with the range of items it covers. A run of chars that is shorter
+/// quoted as `\Q...\E` than escaped one by one is written as one quoted
+/// piece, and a run of case-insensitive items as one piece between `(?i)`
+/// and `(?-i)`.
+fn concatenation_pieces(regexps: &[Regexp]) -> Vec<(usize, usize, String)> {
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while i < regexps.len() {
+        if let Regexp::CaseInsensitive { unicode, .. } = regexps[i] {
+            let inners: Vec<&Regexp> = regexps[i..].iter()
+                .map_while(|regexp| match *regexp {
+                    Regexp::CaseInsensitive { ref inner, unicode: u }
+                        if u == unicode => Some(&**inner),
+                    _ => None
+                })
+                .collect();
+            if inners.len() > 1 {
+                // Digits have no case, so a backreference in the run is
+                // never followed by one.
+                let (on, off) = if unicode {
+                    ("(?i)", "(?-i)")
+                } else {
+                    ("(?i-u)", "(?u-i)")
+                };
+                let text: String = inners.iter()
+                    .map(|inner| write_regexp(inner))
+                    .collect();
+                pieces.push((i, i + inners.len(),
+                             format!("{}{}{}", on, text, off)));
+                i += inners.len();
+                continue;
+            }
+        }
+        let chars: Vec<char> = regexps[i..].iter()
+            .map_while(|regexp| match *regexp {
+                Regexp::Char(c) => Some(c),
+                _ => None
+            })
+            .collect();
+        if !chars.is_empty() {
+            let mut text = String::new();
+            for &c in &chars {
+                push_literal_char(&mut text, c);
+            }
+            // Control chars are kept escaped so that they stay visible.
+            let can_quote = !chars.iter().any(|c| c.is_control())
+                && !chars.windows(2).any(|pair| pair == ['\\', 'E']);
+            if can_quote && chars.len() + 4 < text.chars().count() {
+                let quoted: String = chars.iter().collect();
+                pieces.push((i, i + chars.len(),
+                             format!("\\Q{}\\E", quoted)));
+            } else {
+                for (j, &c) in chars.iter().enumerate() {
+                    let mut text = String::new();
+                    push_literal_char(&mut text, c);
+                    pieces.push((i + j, i + j + 1, text));
+                }
+            }
+            i += chars.len();
+            continue;
+        }
+        let text = write_regexp(&regexps[i]);
+        if concatenation_item_needs_group(regexps, i) {
+            pieces.push((i, i + 1, format!("(?:{})", text)));
+        } else {
+            pieces.push((i, i + 1, text));
+        }
+        i += 1;
+    }
+    pieces
+}
+
+/// The quantifier of the quantified `regexp`, such as `*?` or `{2,3}`.
+fn quantifier_op(regexp: &Regexp) -> String {
+    use self::Regexp::*;
+    let (mut op_char, greed) = match *regexp {
+        Optional(_, greed) => ("?".to_string(), greed),
+        Repeated(_, greed) => ("+".to_string(), greed),
+        OptionalRepeated(_, greed) => ("*".to_string(), greed),
+        Repeat { min, max: None, greed, .. } => (format!("{{{},}}", min), greed),
+        Repeat { min, max: Some(max), greed, .. } => if min == max {
+            (format!("{{{}}}", min), greed)
+        } else {
+            (format!("{{{},{}}}", min, max), greed)
+        },
+        _ => unreachable!()
+    };
+    match greed {
+        Greed::Greedy => (),
+        Greed::Lazy => op_char.push('?'),
+        Greed::Possessive => op_char.push('+')
+    }
+    op_char
+}
+
+/// Returns true if `inner` must be written inside a non-capturing group for
+/// the quantifier `op_char` to apply to all of it.
+fn quantified_needs_group(inner: &Regexp, op_char: &str) -> bool {
+    use self::Regexp::*;
+    match *inner {
+        Char(_) | Capture { .. } | Class { .. } | Perl { .. }
+            | Property { .. } | AnyChar { .. } | Atomic(_)
+            | Backreference(_) | Lookahead { .. }
+            | Lookbehind { .. } | CaseInsensitive { .. } => false,
+        // A `?` or `+` directly after a greedy quantifier would change its
+        // greed instead of applying to it.
+        Optional(_, inner_greed) | Repeated(_, inner_greed)
+            | OptionalRepeated(_, inner_greed)
+            | Repeat { greed: inner_greed, .. }
+            => inner_greed == Greed::Greedy
+               && (op_char.starts_with('?') || op_char.starts_with('+')),
+        _ => true
+    }
+}
+
+/// Pushes the lines of `regexp` laid out for `regexp_to_pretty_string`,
+/// indented by `indent` spaces.
+fn layout_regexp(regexp: &Regexp, indent: usize, width: usize,
+                 lines: &mut Vec<String>) {
+    use self::Regexp::*;
+
+    let text = write_regexp(regexp);
+    if indent + text.chars().count() <= width {
+        lines.push(format!("{:2$}{}", "", text, indent));
+        return;
+    }
     match *regexp {
-        Char(c) => match c {
-            '?' | '+' | '*' | '\\' | '(' | ')' =>
-                '\\'.to_string() + &(c.to_string()),
-            _ => c.to_string()
+        Alternation(ref regexps) => {
+            for (i, alternative) in regexps.iter().enumerate() {
+                if i > 0 {
+                    lines.push(format!("{:1$}|", "", indent));
+                }
+                match *alternative {
+                    Alternation(_) => layout_group("(?:", alternative, "",
+                                                   indent + 2, width, lines),
+                    _ => layout_regexp(alternative, indent + 2, width, lines)
+                }
+            }
         },
-        Concatenation(ref regexps) => regexps.iter().map(regexp_to_string)
-                .collect::<Vec<String>>().join(""),
-        Alternation(ref regexps)
-            => format!("({})", regexps.iter().map(regexp_to_string)
-                       .collect::<Vec<String>>().join("|")),
-        Optional(ref inner_regexp)
-            | Repeated(ref inner_regexp)
-            | OptionalRepeated(ref inner_regexp) => {
-                let op_char = match *regexp { Optional(_) => "?",
-                                              Repeated(_) => "+",
-                                              OptionalRepeated(_) => "*",
-                                              _ => unreachable!() };
-                let text = regexp_to_string(&inner_regexp);
-                match **inner_regexp {
-                    Char(_) | Optional(_) | Repeated(_) | OptionalRepeated(_)
-                        => format!("{}{}", text, op_char),
-                    _ => format!("({}){}", text, op_char)
+        Concatenation(ref regexps) => {
+            // Items are packed onto lines, and only items too wide for a
+            // line of their own are broken up.
+            let mut line = String::new();
+            for (i, end, text) in concatenation_pieces(regexps) {
+                let item = &regexps[i];
+                let len = text.chars().count();
+                if indent + line.chars().count() + len <= width {
+                    line.push_str(&text);
+                    continue;
+                }
+                if !line.is_empty() {
+                    lines.push(format!("{:2$}{}", "", line, indent));
+                    line.clear();
+                }
+                // Runs of items written together are never broken up.
+                if indent + len <= width || end - i > 1 {
+                    line = text;
+                } else if concatenation_item_needs_group(regexps, i) {
+                    layout_group("(?:", item, "", indent, width, lines);
+                } else {
+                    layout_regexp(item, indent, width, lines);
+                }
+            }
+            if !line.is_empty() {
+                lines.push(format!("{:2$}{}", "", line, indent));
+            }
+        },
+        Capture { ref inner, .. } | Atomic(ref inner)
+            | Lookahead { ref inner, .. } | Lookbehind { ref inner, .. } => {
+                layout_group(&group_opener(regexp).unwrap(), inner, "",
+                             indent, width, lines);
+        },
+        Optional(ref inner, _) | Repeated(ref inner, _)
+            | OptionalRepeated(ref inner, _) | Repeat { ref inner, .. } => {
+                let op_char = quantifier_op(regexp);
+                if quantified_needs_group(inner, &op_char) {
+                    layout_group("(?:", inner, &op_char, indent, width, lines);
+                } else {
+                    layout_regexp(inner, indent, width, lines);
+                    lines.last_mut().unwrap().push_str(&op_char);
                 }
+        },
+        _ => lines.push(format!("{:2$}{}", "", text, indent))
+    }
+}
+
+/// Pushes the lines of a group opened with `opener` around `inner` and
+/// closed with `)` followed by `suffix`, with `inner` nested inside it.
+fn layout_group(opener: &str, inner: &Regexp, suffix: &str, indent: usize,
+                width: usize, lines: &mut Vec<String>) {
+    lines.push(format!("{:2$}{}", "", opener, indent));
+    layout_regexp(inner, indent + 4, width, lines);
+    lines.push(format!("{:2$}){}", "", suffix, indent));
+}
+
+/// Returns a pattern that matches exactly `text`, with every metacharacter
+/// in it escaped. Whitespace and `#` are escaped too, so the pattern can
+/// also be used in verbose mode.
+pub fn escape(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len());
+    for c in text.chars() {
+        push_literal_char(&mut pattern, c);
+    }
+    pattern
+}
+
+/// Pushes `c` onto `text` as it is written outside a class, escaped if it
+/// is a metacharacter or a control char, or whitespace or `#`, which verbose
+/// mode would otherwise skip.
+fn push_literal_char(text: &mut String, c: char) {
+    match c {
+        '?' | '+' | '*' | '\\' | '(' | ')' | '|' | '[' | '.' | '^' | '$'
+            | '{' | ' ' | '#' => text.push('\\'),
+        _ => {
+            if push_control_char(text, c) { return; }
+            if c.is_whitespace() {
+                text.push_str(&format!("\\x{{{:X}}}", c as u32));
+                return;
+            }
+        }
+    }
+    text.push(c);
+}
+
+/// Pushes an escape for `c` onto `text` and returns true if `c` is a
+/// control char, which would be hard to read written as itself.
+fn push_control_char(text: &mut String, c: char) -> bool {
+    match c {
+        '\n' => text.push_str("\\n"),
+        '\t' => text.push_str("\\t"),
+        '\r' => text.push_str("\\r"),
+        _ if c.is_control() => {
+            text.push_str(&format!("\\x{{{:X}}}", c as u32));
+        },
+        _ => return false
+    }
+    true
+}
+
+fn class_to_string(class: &CharClass, negated: bool) -> String {
+    fn push_class_char(text: &mut String, c: char) {
+        match c {
+            ']' | '\\' | '-' | '^' | '[' => text.push('\\'),
+            _ => if push_control_char(text, c) { return; }
+        }
+        text.push(c);
+    }
+
+    let mut text = String::from(if negated { "[^" } else { "[" });
+    // A class with no chars cannot be listed, so it is written as one.
+    if class.is_empty() {
+        text.push_str("a&&b]");
+        return text;
+    }
+    for &(start, end) in class.ranges() {
+        push_class_char(&mut text, start);
+        if start != end {
+            text.push('-');
+            push_class_char(&mut text, end);
         }
     }
+    text.push(']');
+    text
 }
 
 pub fn print_regexp(regexp: &Regexp) {
@@ -500,22 +1770,30 @@ fn print_regexp_depth(regexp: &Regexp, depth: i64) {
     }
 
     let text: String = format!("{:?}", *regexp);
-    let text = text[0..text.find('(').unwrap()].to_string();
+    let text = text.split(|c: char| !c.is_alphanumeric()).next().unwrap()
+        .to_string();
 
-    println!("{}: {}", text, regexp_to_string(&regexp));
+    println!("{}: {}", text, regexp_to_string(regexp));
 
     use self::Regexp::*;
     match *regexp {
-        Char(_) => (),
+        Empty | Char(_) | Class { .. } | Perl { .. } | Property { .. }
+            | AnyChar { .. } | Assertion(_) | Backreference(_) => (),
         Concatenation(ref inner_regexps) | Alternation(ref inner_regexps) => {
             for sub_regexp in inner_regexps {
-                print_regexp_depth(&sub_regexp, depth + 1);
+                print_regexp_depth(sub_regexp, depth + 1);
             }
         },
-        Optional(ref inner_regexp)
-            | Repeated(ref inner_regexp)
-            | OptionalRepeated(ref inner_regexp) => {
-                print_regexp_depth(&inner_regexp, depth + 1);
+        Optional(ref inner_regexp, _)
+            | Repeated(ref inner_regexp, _)
+            | OptionalRepeated(ref inner_regexp, _)
+            | Capture { inner: ref inner_regexp, .. }
+            | Repeat { inner: ref inner_regexp, .. }
+            | Atomic(ref inner_regexp)
+            | Lookahead { inner: ref inner_regexp, .. }
+            | Lookbehind { inner: ref inner_regexp, .. }
+            | CaseInsensitive { inner: ref inner_regexp, .. } => {
+                print_regexp_depth(inner_regexp, depth + 1);
             }
     }
 }
@@ -1,16 +1,23 @@
 mod create;
+mod class;
 mod thompson_nfa;
 mod matching;
+mod backtrack;
+mod unicode_tables;
 
 pub use self::create::*;
+pub use self::class::*;
 pub use self::thompson_nfa::*;
 pub use self::matching::*;
+pub use self::backtrack::*;
 
 #[cfg(test)]
 mod tests {
     use super::create::*;
+    use super::class::*;
     use super::thompson_nfa::*;
     use super::matching::*;
+    use super::backtrack::*;
 
     #[test]
     fn is_match_tests() {
@@ -36,8 +43,218 @@ mod tests {
             ("(ab)+cd", "ababcd", true),
             ("colou?r", "color", true),
             ("colou?r", "colour", true),
+            // Unanchored search tests
+            ("b", "ab", true),
+            ("b+c", "aabbbc", true),
+            ("colou?r", "the colour red", true),
+            ("ab", "ba", false),
+            // Class tests
+            ("[a-c]+", "xbcay", true),
+            ("[a-c]", "xyz", false),
+            ("0x[0-9a-fA-F]+", "0xBEEF", true),
+            ("[^0-9]", "123", false),
+            ("[^0-9]", "12a", true),
+            ("[]a]", "]", true),
+            ("[a-]", "-", true),
+            ("[^-]", "-", false),
+            // Dot tests
+            ("a.c", "abc", true),
+            ("a.c", "a.c", true),
+            ("a.c", "ac", false),
+            ("a.c", "a\nc", false),
+            (".+", "日本", true),
+            // Anchor tests
+            ("^ab$", "ab", true),
+            ("^ab$", "abc", false),
+            ("^ab$", "cab", false),
+            ("b$", "abab", true),
+            ("^b", "abab", false),
+            ("\\Aa+\\z", "aaa", true),
+            ("\\Aa+\\z", "aaa\n", false),
+            ("a^", "a", false),
+            // Counted repetition tests
+            ("^a{3}$", "aaa", true),
+            ("^a{3}$", "aa", false),
+            ("^a{3}$", "aaaa", false),
+            ("^[a-z0-9]{3,8}$", "abc123", true),
+            ("^[a-z0-9]{3,8}$", "ab", false),
+            ("^[a-z0-9]{3,8}$", "abcdefghi", false),
+            ("^(ab){2,}$", "ababab", true),
+            ("^(ab){2,}$", "ab", false),
+            ("^x{0}y$", "y", true),
+            ("^x{0,1}y$", "xy", true),
+            // Atomic group and possessive quantifier tests
+            ("a++a", "aaa", false),
+            ("a*+b", "aab", true),
+            ("^a?+a$", "a", false),
+            ("^a{1,3}+a$", "aaa", false),
+            ("^a{1,3}+a$", "aaaa", true),
+            ("(?>a+)a", "aaa", false),
+            ("(?>a|ab)c", "abc", false),
+            ("(?>ab|a)c", "abc", true),
+            ("(?>ab|a)c", "ac", true),
+            ("\"(?>[^\"]*)\"", "say \"hi\"", true),
+            // Inline flag tests
+            ("a(?s).b", "a\nb", true),
+            ("a(?s:.)b", "a\nb", true),
+            ("(?s:a).b", "a\nb", false),
+            ("(?m)^b$", "a\nb\nc", true),
+            ("(?m-m)^b$", "a\nb\nc", false),
+            // Escape tests
+            ("\\(a\\)", "(a)", true),
+            ("\\(a\\)", "a", false),
+            ("a\\.b", "axb", false),
+            ("[\\]\\\\]+", "x]\\", true),
+            ("\\x61\\u{62}\\n", "ab\n", true),
+            ("\\t", " ", false),
+            // Predefined class tests
+            ("^\\d+$", "2024", true),
+            ("^\\d+$", "20x4", false),
+            ("^\\d+$", "١٢٣", true),
+            ("^(?-u:\\d)+$", "١٢٣", false),
+            ("^\\w+\\s\\w+$", "naïve café", true),
+            ("^(?-u)\\w+\\s\\w+$", "naïve café", false),
+            ("^\\S+\\s*$", "word\u{3000}", true),
+            ("\\D\\W", "9a-", true),
+            ("^[\\d_]+$", "4_2", true),
+            ("^[^\\W_]+$", "a_b", false),
+            ("^[\\D]$", "7", false),
+            // Unicode property tests
+            ("^\\p{L}+$", "Zoë", true),
+            ("^\\p{L}+$", "Zoë2", false),
+            ("^\\pL\\pN$", "x٣", true),
+            ("^\\p{Greek}+$", "λόγος", true),
+            ("^\\p{greek}+$", "logos", false),
+            ("^\\P{Greek}+$", "logos", true),
+            ("^\\p{Lu}\\p{Ll}+$", "Élan", true),
+            ("^\\p{Uppercase_Letter}", "élan", false),
+            ("^[\\p{Han}\\p{Hiragana}]+$", "漢字ひらがな", true),
+            ("^[^\\P{Cyrillic}]+$", "Москва", true),
+            // Word boundary tests
+            ("\\bcat\\b", "concatenate", false),
+            ("\\bcat\\b", "a cat!", true),
+            ("\\Bcat\\B", "concatenate", true),
+            ("\\bcat", "cat", true),
+            ("cat\\b", "cat", true),
+            ("\\b", "", false),
+            ("\\B", "", true),
+            ("^\\b", " x", false),
+            ("\\bé", "café", false),
+            ("(?-u:\\bé)", "café", true),
+            // Backreference tests
+            ("(\\w+) \\1", "hello hello", true),
+            ("(\\w+) \\1", "hello world", false),
+            ("^(a+)b\\1$", "aabaa", true),
+            ("^(a+)b\\1$", "aaba", false),
+            ("(?<q>['\"]).*\\k<q>", "say 'hi\"", false),
+            ("(?<q>['\"]).*\\k<q>", "say \"hi\"", true),
+            ("(a)|\\1b", "b", false),
+            ("^(?:(a)|b)\\1$", "b", false),
+            ("(?>(a+))\\1", "aaaa", false),
+            ("(a+)\\1", "aaa", true),
+            // Lookaround tests
+            ("^(?=.*\\d)(?=.*[a-z])(?!.*\\s).{8,}$", "hunter42x", true),
+            ("^(?=.*\\d)(?=.*[a-z])(?!.*\\s).{8,}$", "hunter4", false),
+            ("^(?=.*\\d)(?=.*[a-z])(?!.*\\s).{8,}$", "hunter 42", false),
+            ("^(?=.*\\d)(?=.*[a-z])(?!.*\\s).{8,}$", "HUNTER4242", false),
+            ("(?<=\\$)\\d+", "cost: $42", true),
+            ("(?<=\\$)\\d+", "cost: 42", false),
+            ("(?<!\\$)\\b\\d+", "$42", false),
+            ("(?<=ab|b)c", "bc", true),
+            ("(?<=a{2,3})b", "ab", false),
+            ("(?<=^a)b", "ab", true),
+            ("(?<=^a)b", "cab", false),
+            ("(?<=\\b)x", "x", true),
+            // Verbose mode and comment tests
+            ("(?x) a b c", "abc", true),
+            ("(?x) a b c", "a b c", false),
+            ("(?x) a\\ b [ ]c", "a b c", true),
+            ("(?x) a \\# b # not part of the pattern", "a#b", true),
+            ("(?x: a b ) c", "ab c", true),
+            ("a(?#comment)b", "ab", true),
+            ("a(?#comment)*b", "aaab", true),
+            ("a (?#comment)", "a ", true),
+            // POSIX and equivalence class tests
+            ("^[[:alpha:]]+$", "abcXYZ", true),
+            ("^[[:alpha:]]+$", "abc1", false),
+            ("^[[:^digit:][:space:]]+$", "a b\tc", true),
+            ("^[^[:xdigit:]]$", "g", true),
+            ("^[^[:xdigit:]]$", "F", false),
+            ("^[[:punct:]]+$", "!?[]{}", true),
+            ("^[[:alpha:]]$", "é", false),
+            ("^r[[=e=]]sum[[=e=]]$", "résumé", true),
+            ("^r[[=é=]]sum[[=e=]]$", "resume", true),
+            ("^[[=e=]]$", "f", false),
+            ("^[[=-=]x]+$", "x-x", true),
+            ("^[\\[:]+$", "[:", true),
+            // Class set operation tests
+            ("^[\\p{Greek}&&\\p{L}--[αβ]]+$", "γδ", true),
+            ("^[\\p{Greek}&&\\p{L}--[αβ]]+$", "γα", false),
+            ("^[\\p{Greek}&&\\p{L}--[αβ]]+$", "ϡ͵", false),
+            ("^[\\w--\\d]+$", "ab_c", true),
+            ("^[\\w--\\d]+$", "a1", false),
+            ("^[a-z&&[^aeiou]]+$", "rhythm", true),
+            ("^[a-z&&[^aeiou]]+$", "rhyme", false),
+            ("^[a-c~~b-d]+$", "ad", true),
+            ("^[a-c~~b-d]+$", "b", false),
+            ("^[a-z&&m-q--n]+$", "mopq", true),
+            ("^[a-z&&m-q--n]+$", "mn", false),
+            ("^[[a-c][x-z]]+$", "cx", true),
+            ("^[^[a-c][x-z]]$", "d", true),
+            ("[a&&b]", "ab", false),
+            ("^[a&b~c]+$", "&~", true),
+            // Quoting tests
+            ("^\\Qa.b\\E$", "a.b", true),
+            ("^\\Qa.b\\E$", "axb", false),
+            ("^\\Q(x)\\E+$", "(x)))", true),
+            ("^\\Q(x)\\E+$", "(x)(x)", false),
+            ("\\Q[a-z]", "[a-z]", true),
+            ("\\Q[a-z]", "q", false),
+            ("(?x)^\\Q a # b\\E$", " a # b", true),
+            // Case-insensitive tests
+            ("(?i)^error$", "ERROR", true),
+            ("(?i)^error$", "Error", true),
+            ("^(?i)err(?-i)or$", "ERRor", true),
+            ("^(?i)err(?-i)or$", "ERROR", false),
+            ("^(?i:a)b$", "AB", false),
+            ("(?i)ſ", "S", true),
+            ("(?i)s", "ſ", true),
+            ("(?i)k", "\u{212A}", true),
+            ("(?i-u)k", "\u{212A}", false),
+            ("(?i-u)k", "K", true),
+            ("(?i)σ", "ς", true),
+            ("(?i)é", "É", true),
+            ("(?i)^[a-c]+$", "AbC", true),
+            ("(?i)^[^a]$", "A", false),
+            ("(?i)^\\p{Lu}+$", "aB", true),
+            ("(?i)^(a+)\\1$", "aAA", false),
+            ("(?i)^(a+)\\1$", "aAaA", true),
+            ("(?i)^(k) \\1$", "k \u{212A}", true),
+            ("(?i-u)^(k) \\1$", "k \u{212A}", false),
+            ("(?i)^(\\w+) \\1$", "Straße STRASSE", false),
+            // Empty regexp tests
+            ("", "", true),
+            ("", "abc", true),
+            ("a|", "", true),
+            ("^(|b)$", "", true),
+            ("^(|b)$", "b", true),
+            ("^()$", "", true),
+            ("^()$", "a", false),
+            ("a?", "", true),
+            ("^a*$", "", true),
+            ("^(?:)+$", "", true),
+            ("^x(?:)*y$", "xy", true),
+            ("^(?:)*$", "", true),
+            ("^()*$", "", true),
+            ("^(|a)*$", "a", true),
+            ("^(|a)*$", "b", false),
+            ("(?:)*$", "x", true),
+            ("(?<=)a", "a", true),
+            ("(?!)", "", false),
+            ("^$|a", "", true),
+            ("^(?:a|)\\b$", "", false),
         ];
-        println!("");
+        println!();
         for test_case in test_cases {
             let regexp = &Regexp::from_string(test_case.0).unwrap();
             let input = test_case.1;
@@ -62,40 +279,388 @@ mod tests {
 
     #[test]
     fn thompson_vm_match_tests() {
-
+        let test_cases = vec![
+            // (regexp, input, anchoring, expected result)
+            ("b", "ab", Anchoring::Unanchored, true),
+            ("b", "ab", Anchoring::Start, false),
+            ("a+b", "caab", Anchoring::Unanchored, true),
+            ("a+b", "caab", Anchoring::Start, false),
+            ("a+b", "aabc", Anchoring::Start, true),
+            ("(ab)+c", "abaababc", Anchoring::Unanchored, true),
+        ];
+        for test_case in test_cases {
+            let regexp = Regexp::from_string(test_case.0).unwrap();
+            let insts = compile_regexp(&regexp);
+            let result = thompson_vm(&insts, regexp.captures_len(),
+                                     test_case.1, test_case.2)
+                .is_some();
+            assert_eq!(result, test_case.3, "regexp \"{}\" on input \"{}\" \
+                                            with {:?}",
+                       test_case.0, test_case.1, test_case.2);
+        }
     }
 
     #[test]
     fn matching_test() {
-        // let regexp = regexp_from_string("a+q?c?").unwrap();
-        // let string = "aqab";
-        // let expected_match = "aq";
-        // let test_cases = vec![
-        //     // (regexp, input, expected output)
-        //     ("a", "a", "a"),
-        //     ("a+", "aa", "aa"),
-        //     ("a?", "ab", "a"),
-        //     ("a+", "aba", "a"),
-        //     ("a+b?", "aaab", "aaab"),
-        //     ("b?", "a", ""),
-        //     ("b?a+c", "baaac", "baaac"),
-        //     ("b?a+c", "aac", "aac")
-        // ];
-        // for test_case in test_cases {
-        //     let regexp = regexp_from_string(test_case.0).unwrap();
-        //     let input = test_case.1;
-        //     let expected_match = test_case.2;
-        //     match first_match_anchored_start(&regexp, input) {
-        //         Some(matched_text) => if matched_text != expected_match {
-        //             panic!("\n\tBad regexp match for regexp {:?}: \
-        //                     matched_text({}) != expected_match({})",
-        //                    regexp, matched_text, expected_match)
-        //         },
-        //         None => panic!("Regexp({}, {:?}) found no match on \"{}\" \
-        //                         when \"{}\" was expected",
-        //                        test_case.0, regexp, test_case.1, test_case.2)
-        //     }
-        // }
+        let test_cases = vec![
+            // (regexp, input, expected output)
+            ("a", "a", "a"),
+            ("a+", "aa", "aa"),
+            ("a?", "ab", "a"),
+            ("a+", "aba", "a"),
+            ("a+b?", "aaab", "aaab"),
+            ("b?", "a", ""),
+            ("b?a+c", "baaac", "baaac"),
+            ("b?a+c", "aac", "aac"),
+            ("b+", "aabbba", "bbb"),
+            ("(ab)+", "xxababa", "abab"),
+            ("a|ab", "ab", "a"),
+            ("a{2,3}", "aaaa", "aaa"),
+            ("a{2,}", "aaaaa", "aaaaa"),
+            ("(|a)*", "a", ""),
+            ("(?:\\b|a)*", "a", ""),
+            ("(?:a|)*b", "aab", "aab"),
+            ("(?:|a){2,}", "aa", ""),
+            ("(?:a|){2,}b", "aab", "aab"),
+            // Lazy quantifier tests
+            ("a+?", "aaa", "a"),
+            ("a*?b", "aab", "aab"),
+            ("a??", "a", ""),
+            ("a{2,}?", "aaaaa", "aa"),
+            ("\".*?\"", "say \"hi\" and \"bye\"", "\"hi\""),
+            ("\".*\"", "say \"hi\" and \"bye\"", "\"hi\" and \"bye\""),
+            ("(?:a??)*", "a", ""),
+            ("(?:a*?)*", "aa", ""),
+            ("(?:a??){2,}", "aa", ""),
+            ("(?:a??)+b", "aab", "aab"),
+            ("(?>ab)c|abd", "abd", "abd"),
+            ("x(?>a+)|xaab", "xaab", "xaa"),
+            ("(?>a*)+b", "aab", "aab"),
+            ("\\b\\w+\\b", "  foo_bar9 baz", "foo_bar9"),
+            ("\\w+\\B", "abc def", "ab"),
+            ("\\b[a-z]+\\b", "naïve idea", "idea"),
+            ("(\\w)\\1", "abccd", "cc"),
+            ("\\b(\\w+)\\s+\\1\\b", "it is the the end", "the the"),
+            ("(a*)\\1", "aaaaa", "aaaa"),
+            ("(a*?)\\1b", "aab", "aab"),
+            ("\\w+(?=!)", "hi there!", "there"),
+            ("q(?!u)\\w", "quit qat", "qa"),
+            ("(?<=-)\\w+", "a-bc-d", "bc"),
+            ("(?<![\\w-])\\w+", "-a bc", "bc"),
+            ("(?<=a(?=b)b)c", "abc", "c"),
+            ("(?=(\\w+))\\1!", "abc!", "abc!"),
+        ];
+        for test_case in test_cases {
+            let regexp = Regexp::from_string(test_case.0).unwrap();
+            let input = test_case.1;
+            let expected_match = test_case.2;
+            match find(&regexp, input) {
+                Some(m) => if m.as_str() != expected_match {
+                    panic!("\n\tBad regexp match for regexp {:?}: \
+                            matched_text({}) != expected_match({})",
+                           regexp, m.as_str(), expected_match)
+                },
+                None => panic!("Regexp({}, {:?}) found no match on \"{}\" \
+                                when \"{}\" was expected",
+                               test_case.0, regexp, test_case.1, test_case.2)
+            }
+        }
+    }
+
+    #[test]
+    fn match_offsets() {
+        let regexp = Regexp::from_string("éc+").unwrap();
+        assert_eq!(find(&regexp, "aéébcccé"), None);
+        let m = find(&regexp, "aébéccé").unwrap();
+        assert_eq!((m.start(), m.end()), (4, 8));
+        assert_eq!((m.char_start(), m.char_end()), (3, 6));
+        assert_eq!(m.as_str(), "écc");
+
+        assert_eq!(find_with(&regexp, "aécc", Anchoring::Start), None);
+        let m = find_with(&regexp, "écca", Anchoring::Start).unwrap();
+        assert_eq!((m.start(), m.end()), (0, 4));
+    }
+
+    #[test]
+    fn captures_test() {
+        let test_cases = vec![
+            // (regexp, input, expected group texts)
+            ("(a+)(b+)", "xaabbb", vec![Some("aabbb"), Some("aa"), Some("bbb")]),
+            ("(a|b)+", "abba", vec![Some("abba"), Some("a")]),
+            ("(a)|(b)", "b", vec![Some("b"), None, Some("b")]),
+            ("((a)b)?c", "c", vec![Some("c"), None, None]),
+            ("(a*)(a)", "aaa", vec![Some("aaa"), Some("aa"), Some("a")]),
+            ("(a?)(a*)", "aa", vec![Some("aa"), Some("a"), Some("a")]),
+            ("(?>(a+))(b)", "aab", vec![Some("aab"), Some("aa"), Some("b")]),
+            ("(?>(a)|(b))+c", "abc", vec![Some("abc"), Some("a"), Some("b")]),
+            ("(?:a|b)+(c)", "abc", vec![Some("abc"), Some("c")]),
+            ("(?P<x>a)(?:b)(?<y>c)", "abc",
+             vec![Some("abc"), Some("a"), Some("c")]),
+            ("(a*)+b", "aab", vec![Some("aab"), Some("aa")]),
+            ("(a|ab)(c|bcd)(d*)", "abcd",
+             vec![Some("abcd"), Some("a"), Some("bcd"), Some("")]),
+            ("(a+?)(a*)", "aaa", vec![Some("aaa"), Some("a"), Some("aa")]),
+            ("(?=(a+))a", "aaa", vec![Some("a"), Some("aaa")]),
+            ("(?<=(a|ba))c", "bac", vec![Some("c"), Some("ba")]),
+            ("(?!(a))b", "b", vec![Some("b"), None]),
+            ("(|b)c", "bc", vec![Some("bc"), Some("b")]),
+            ("(b|)c", "c", vec![Some("c"), Some("")]),
+            ("()", "", vec![Some(""), Some("")]),
+            ("(a){0}b", "ab", vec![Some("b"), None]),
+            ("(x)?(?:z|(y){0})", "z", vec![Some("z"), None, None]),
+            ("(|a)*", "a", vec![Some(""), Some("")]),
+            ("()*", "", vec![Some(""), Some("")]),
+        ];
+        for test_case in test_cases {
+            let regexp = Regexp::from_string(test_case.0).unwrap();
+            let caps = captures(&regexp, test_case.1).unwrap();
+            // Both engines must agree on programs either can run.
+            let insts = compile_regexp(&regexp);
+            let len = regexp.captures_len();
+            assert_eq!(backtrack(&insts, len, test_case.1,
+                                 Anchoring::Unanchored),
+                       thompson_vm(&insts, len, test_case.1,
+                                   Anchoring::Unanchored),
+                       "regexp \"{}\" on input \"{}\"",
+                       test_case.0, test_case.1);
+            let groups: Vec<Option<&str>> = (0..caps.len())
+                .map(|i| caps.get(i).map(|m| m.as_str()))
+                .collect();
+            assert_eq!(groups, test_case.2, "regexp \"{}\" on input \"{}\"",
+                       test_case.0, test_case.1);
+        }
+
+        // Spans as the `regex` and `fancy-regex` crates report them. A loop
+        // whose preferred iteration matches empty stops there.
+        let test_cases = vec![
+            ("(|a)*", "a", vec![Some(0), Some(0), Some(0), Some(0)]),
+            ("(?:\\b|a)*", "a", vec![Some(0), Some(0)]),
+            ("()*", "", vec![Some(0), Some(0), Some(0), Some(0)]),
+            ("(a|)*b", "aab",
+             vec![Some(0), Some(3), Some(1), Some(2)]),
+            ("(|a){2,}", "aa", vec![Some(0), Some(0), Some(0), Some(0)]),
+            ("(a|){2,}b", "aab",
+             vec![Some(0), Some(3), Some(1), Some(2)]),
+            ("(a??)*", "a", vec![Some(0), Some(0), Some(0), Some(0)]),
+            ("(a+?)*", "aaa", vec![Some(0), Some(3), Some(2), Some(3)]),
+            ("(a*?)*?b", "aab", vec![Some(0), Some(3), Some(0), Some(2)]),
+            ("(?:)*", "ab", vec![Some(0), Some(0)]),
+            ("()*a", "a", vec![Some(0), Some(1), Some(0), Some(0)]),
+            ("(|a)*b", "ab", vec![Some(0), Some(2), Some(0), Some(1)]),
+            ("(()|a)*", "a",
+             vec![Some(0), Some(0), Some(0), Some(0), Some(0), Some(0)]),
+            ("(a|())*", "aa",
+             vec![Some(0), Some(2), Some(1), Some(2), None, None]),
+        ];
+        for test_case in test_cases {
+            let regexp = Regexp::from_string(test_case.0).unwrap();
+            let insts = compile_regexp(&regexp);
+            let len = regexp.captures_len();
+            for &anchoring in &[Anchoring::Unanchored, Anchoring::Start] {
+                assert_eq!(thompson_vm(&insts, len, test_case.1, anchoring),
+                           Some(test_case.2.clone()),
+                           "regexp \"{}\" on input \"{}\"",
+                           test_case.0, test_case.1);
+                assert_eq!(backtrack(&insts, len, test_case.1, anchoring),
+                           Some(test_case.2.clone()),
+                           "regexp \"{}\" on input \"{}\"",
+                           test_case.0, test_case.1);
+            }
+        }
+
+        let regexp = Regexp::from_string("(b)(c)").unwrap();
+        let caps = captures(&regexp, "abcd").unwrap();
+        assert_eq!(&caps[2], "c");
+        assert_eq!((caps.get(1).unwrap().start(),
+                    caps.get(1).unwrap().end()), (1, 2));
+        assert_eq!(captures(&regexp, "acb"), None);
+    }
+
+    #[test]
+    fn compiled_regex_test() {
+        let regex = Regex::new("(a+)(b)?").unwrap();
+        assert_eq!(regex.as_str(), "(a+)(b)?");
+        assert_eq!(regex.captures_len(), 3);
+        assert_eq!(regex.anchoring(), Anchoring::Unanchored);
+        assert_eq!(regex.insts(), &compile_regexp(regex.regexp())[..]);
+        // Each atomic sub-program runs once per position, so nesting them
+        // does not multiply the time taken.
+        let nested = Regex::new("(?>(?:(?>(?:(?>a+)b|a)+)b|a)+)c").unwrap();
+        assert!(!nested.is_match(&"a".repeat(200)));
+        assert!(nested.is_match(&("a".repeat(200) + "bbc")));
+        // The same goes for lookarounds.
+        let nested = Regex::new("(?=(?:(?=(?:(?=a*)a)*)a)*)b").unwrap();
+        assert!(!nested.is_match(&"a".repeat(200)));
+        assert_eq!(nested.find(&("a".repeat(200) + "b")).map(|m| m.start()),
+                   Some(200));
+
+        // A group repeated zero times compiles to nothing but still counts.
+        let zero = Regex::new("(a){0}").unwrap();
+        assert_eq!(zero.captures_len(), 2);
+        assert_eq!(zero.captures("").unwrap().len(), 2);
+        // Too few groups from the caller still leaves room for every `Save`.
+        let insts = compile_regexp(&Regexp::from_string("(a)(b)").unwrap());
+        let slots = vec![Some(0), Some(2), Some(0), Some(1), Some(1), Some(2)];
+        assert_eq!(thompson_vm(&insts, 0, "ab", Anchoring::Unanchored),
+                   Some(slots.clone()));
+        assert_eq!(backtrack(&insts, 1, "ab", Anchoring::Unanchored),
+                   Some(slots));
+
+        for input in &["xaab", "ab", "ba"] {
+            assert_eq!(regex.find(input),
+                       find(regex.regexp(), input));
+            assert_eq!(regex.captures(input),
+                       captures(regex.regexp(), input));
+        }
+        assert!(!regex.is_match("bbb"));
+
+        let anchored = RegexBuilder::new("a+")
+            .anchoring(Anchoring::Start)
+            .build()
+            .unwrap();
+        assert!(anchored.is_match("aab"));
+        assert!(!anchored.is_match("baa"));
+
+        let regex = Regex::new("a.+c").unwrap();
+        assert_eq!(regex.find("xa\nbc a\tbc").unwrap().as_str(), "a\tbc");
+        let dot_all = RegexBuilder::new("a.+c")
+            .dot_matches_new_line(true)
+            .build()
+            .unwrap();
+        assert_eq!(dot_all.find("xa\nbc a\tbc").unwrap().as_str(),
+                   "a\nbc a\tbc");
+
+        let single_line = Regex::new("^[a-z]+$").unwrap();
+        assert!(!single_line.is_match("one\ntwo"));
+        let multi_line = RegexBuilder::new("^[a-z]+$")
+            .multi_line(true)
+            .build()
+            .unwrap();
+        assert_eq!(multi_line.find("1\ntwo\n3").unwrap().as_str(), "two");
+        assert_eq!(multi_line.find("1\r\ntwo\r\n3").unwrap().as_str(),
+                   "two");
+        let line_ends = RegexBuilder::new("[^a-z]$")
+            .multi_line(true)
+            .build()
+            .unwrap();
+        // `$` does not match between the `\r` and `\n` of a CRLF.
+        assert_eq!(line_ends.find("ab\r\n1\r\n").unwrap().as_str(), "1");
+        let text_anchors = RegexBuilder::new("\\A[a-z]+\\z")
+            .multi_line(true)
+            .build()
+            .unwrap();
+        assert!(!text_anchors.is_match("one\ntwo"));
+
+        let date = RegexBuilder::new("
+                (?P<year>\\d{4}) - # the year
+                (?P<month>\\d{2})  # then the month
+            ")
+            .ignore_whitespace(true)
+            .build()
+            .unwrap();
+        let caps = date.captures("on 2024-06-01").unwrap();
+        assert_eq!((&caps["year"], &caps["month"]), ("2024", "06"));
+
+        let ascii_words = RegexBuilder::new("\\w+")
+            .unicode(false)
+            .build()
+            .unwrap();
+        assert_eq!(ascii_words.find("größe").unwrap().as_str(), "gr");
+        let any_case = RegexBuilder::new("^error: (?-i:E)\\d+$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(any_case.is_match("ERROR: E42"));
+        assert!(any_case.is_match("Error: E1"));
+        assert!(!any_case.is_match("error: e1"));
+        assert!(!any_case.is_backtracking());
+
+        assert!(!regex.is_backtracking());
+        let repeated = Regex::new("(?P<word>\\w+) \\k<word>").unwrap();
+        assert!(repeated.is_backtracking());
+        assert_eq!(repeated.insts()[5], Inst::Backreference(1));
+        let caps = repeated.captures("so so good").unwrap();
+        assert_eq!((&caps[0], &caps["word"]), ("so so", "so"));
+        let anchored = RegexBuilder::new("(a)\\1")
+            .anchoring(Anchoring::Start)
+            .build()
+            .unwrap();
+        assert!(!anchored.is_match("baa"));
+        // The backtracker keeps its own stack rather than recursing.
+        let long_input = "a".repeat(100_000);
+        let long_match = Regex::new("(a)\\1a*").unwrap().find(&long_input);
+        assert_eq!(long_match.map(|m| m.end()), Some(100_000));
+
+        assert_eq!(Regex::new("(a").unwrap_err(),
+                   RegexpError::UnmatchedParenthesis(0));
+
+        let log_line = Regex::new("([0-9]+)? (?P<user>[a-z]+) \
+                                   (?<action>[a-z]+)").unwrap();
+        assert_eq!(log_line.captures_len(), 4);
+        assert_eq!(log_line.capture_index("user"), Some(2));
+        assert_eq!(log_line.capture_index("action"), Some(3));
+        assert_eq!(log_line.capture_index("missing"), None);
+        let caps = log_line.captures("> root login").unwrap();
+        assert_eq!(&caps["user"], "root");
+        assert_eq!(&caps["action"], "login");
+        assert_eq!(caps.name("action"), caps.get(3));
+        assert_eq!(caps.name("missing"), None);
+    }
+
+    #[test]
+    fn char_class_test() {
+        let digits = PerlClass::Digit.to_class(false);
+        assert_eq!(digits.ranges(), &[('0', '9')]);
+        assert!(PerlClass::Word.to_class(true).contains('λ'));
+        assert!(!PerlClass::Word.to_class(false).contains('λ'));
+        assert!(PerlClass::Space.to_class(true).contains('\u{2028}'));
+        let letters = unicode_property("Letter").unwrap();
+        assert_eq!(Some(letters.clone()), unicode_property("l"));
+        assert!(letters.contains('ß') && !letters.contains('1'));
+        assert_eq!(unicode_property("Old Italic"),
+                   unicode_property("old_italic"));
+        assert_eq!(unicode_property("Klingon"), None);
+
+        let class = CharClass::new(vec![('m', 'p'), ('a', 'c'), ('d', 'f'),
+                                        ('x', 'x'), ('n', 'z')]);
+        assert_eq!(class.ranges(), &[('a', 'f'), ('m', 'z')]);
+        assert!(class.contains('a') && class.contains('e')
+                && class.contains('q'));
+        assert!(!class.contains('g') && !class.contains('A'));
+
+        let negated = class.negate();
+        assert_eq!(negated.ranges(), &[('\0', '`'), ('g', 'l'),
+                                       ('{', '\u{10FFFF}')]);
+        assert_eq!(negated.negate(), class);
+        assert_eq!(CharClass::empty().negate().negate(), CharClass::empty());
+
+        let surrogate_gap = CharClass::new(vec![('\u{D7FF}', '\u{D7FF}'),
+                                                ('\u{E000}', '\u{E000}')]);
+        assert_eq!(surrogate_gap.ranges(), &[('\u{D7FF}', '\u{E000}')]);
+        assert_eq!(class.union(&negated).negate(), CharClass::empty());
+
+        let other = CharClass::new(vec![('d', 'n'), ('y', '~')]);
+        assert_eq!(class.intersection(&other).ranges(),
+                   &[('d', 'f'), ('m', 'n'), ('y', 'z')]);
+        assert_eq!(class.difference(&other).ranges(),
+                   &[('a', 'c'), ('o', 'x')]);
+        assert_eq!(class.symmetric_difference(&other).ranges(),
+                   &[('a', 'c'), ('g', 'l'), ('o', 'x'), ('{', '~')]);
+        assert_eq!(class.intersection(&negated), CharClass::empty());
+        assert_eq!(class.difference(&CharClass::empty()), class);
+
+        let letters = CharClass::new(vec![('S', 'T'), ('é', 'é')]);
+        assert_eq!(letters.case_fold(true).ranges(),
+                   &[('S', 'T'), ('s', 't'), ('É', 'É'), ('é', 'é'),
+                     ('ſ', 'ſ')]);
+        assert_eq!(letters.case_fold(false).ranges(),
+                   &[('S', 'T'), ('s', 't'), ('é', 'é')]);
+        assert_eq!(CharClass::new(vec![('0', '@')]).case_fold(true),
+                   CharClass::new(vec![('0', '@')]));
+        assert!(eq_ignoring_case('\u{212A}', 'k', true));
+        assert!(!eq_ignoring_case('\u{212A}', 'k', false));
+        assert!(eq_ignoring_case('Q', 'q', false));
+        assert!(!eq_ignoring_case('ß', 's', true));
     }
 
     #[test]
@@ -108,19 +673,83 @@ mod tests {
         let oprep_insts = compile_regexp(&Regexp::from_string("a*(bc)*").unwrap());
 
         assert_eq!(con_insts, vec![Char('a'), Char('b'), Char('c'), Match]);
-        assert_eq!(alt_insts, vec![Char('a'), Jump(5),
-                                   Char('b'), Jump(5),
+        assert_eq!(alt_insts, vec![Split(1, 3), Char('a'), Jump(7),
+                                   Split(4, 6), Char('b'), Jump(7),
                                    Char('c'), Match]);
         assert_eq!(opt_insts, vec![Split(1, 2), Char('a'),
-                                   Split(3, 5),
-                                   Char('b'), Char('c'),
+                                   Split(3, 7),
+                                   Save(2), Char('b'), Char('c'), Save(3),
                                    Match]);
         assert_eq!(rep_insts, vec![Char('a'), Split(0, 2),
-                                   Char('b'), Char('c'), Split(2, 5),
+                                   Save(2), Char('b'), Char('c'), Save(3),
+                                   Split(2, 7),
                                    Match]);
         assert_eq!(oprep_insts, vec![Split(1, 3), Char('a'), Jump(0),
-                                     Split(4, 7), Char('b'), Char('c'), Jump(3),
-                                     Match])
+                                     Split(4, 9),
+                                     Save(2), Char('b'), Char('c'), Save(3),
+                                     Jump(3),
+                                     Match]);
+        // A body that can match empty repeats through a trailing `Split`.
+        let empty_insts = compile_regexp(&Regexp::from_string("(a?)*")
+                                         .unwrap());
+        assert_eq!(empty_insts, vec![Split(1, 6),
+                                     Save(2), Split(3, 4), Char('a'), Save(3),
+                                     Split(1, 6),
+                                     Match]);
+
+        let lazy_insts = compile_regexp(&Regexp::from_string("a??b+?c*?")
+                                        .unwrap());
+        assert_eq!(lazy_insts, vec![Split(2, 1), Char('a'),
+                                    Char('b'), Split(4, 2),
+                                    Split(7, 5), Char('c'), Jump(4),
+                                    Match]);
+
+        let folded_insts = compile_regexp(&Regexp::from_string("(?i)k1[^x]")
+                                          .unwrap());
+        assert_eq!(folded_insts, vec![
+            Class(CharClass::new(vec![('K', 'K'), ('k', 'k'),
+                                      ('\u{212A}', '\u{212A}')])),
+            Char('1'),
+            Class(CharClass::new(vec![('X', 'X'), ('x', 'x')]).negate()),
+            Match
+        ]);
+    }
+
+    #[test]
+    fn program_cost_test() {
+        let patterns = ["", "abc", "a|b|c", "a?(bc)*+", "(a{2,5}b){3,}?",
+                        "[a-z\\d]\\pL\\w(?i)k", "(?>a)(?=b)(?<!c)", "(?i)(a)\\1",
+                        "(?:a{1,3}+|)x{0}y{0,}+", "(?u)\\s[^\\D]\\p{Greek}"];
+        for pattern in patterns.iter() {
+            let regexp = Regexp::from_string(pattern).unwrap();
+            let insts = compile_regexp(&regexp);
+            // `compile_regexp` adds a final `Match`.
+            let cost = insts.len() - 1 + insts.iter().map(|inst| match *inst {
+                Inst::Class(ref class) => class.ranges().len(),
+                _ => 0
+            }).sum::<usize>();
+            assert_eq!(program_cost(&regexp), Some(cost), "{}", pattern);
+        }
+
+        let mut nested = Regexp::Char('a');
+        for _ in 0..3 {
+            nested = Regexp::Repeat {
+                inner: Box::new(nested),
+                min: u32::MAX, max: None, greed: Greed::Greedy
+            };
+        }
+        assert_eq!(program_cost(&nested), None);
+
+        // A hand-built repeat with its bounds reversed has no optional copies.
+        let reversed = Regexp::Repeat {
+            inner: Box::new(Regexp::Char('a')),
+            min: 3, max: Some(1), greed: Greed::Greedy
+        };
+        assert_eq!(program_cost(&reversed), Some(3));
+        assert_eq!(compile_regexp(&reversed).len(), 4);
+
+        assert!(Regex::new("x{1000}").is_ok());
+        assert!(Regex::new("x{1000}y{1000}z{1000}").is_ok());
     }
 }
 
@@ -1,8 +1,354 @@
-use create::Regexp;
+use std::collections::HashMap;
+use std::ops::Index;
+use std::sync::Arc;
+
+use create::{Flags, Regexp, RegexpError};
+use backtrack;
 use thompson_nfa;
+use thompson_nfa::{Anchoring, Inst};
+
+/// A single match of a regexp in some input text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Match<'t> {
+    text: &'t str,
+    start: usize,
+    end: usize
+}
+
+impl<'t> Match<'t> {
+    fn new(text: &'t str, start: usize, end: usize) -> Match<'t> {
+        Match { text, start, end }
+    }
+
+    /// Byte offset of the start of the match.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Byte offset just past the end of the match.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Char offset of the start of the match.
+    pub fn char_start(&self) -> usize {
+        self.text[..self.start].chars().count()
+    }
+
+    /// Char offset just past the end of the match.
+    pub fn char_end(&self) -> usize {
+        self.char_start() + self.as_str().chars().count()
+    }
+
+    /// The matched text.
+    pub fn as_str(&self) -> &'t str {
+        &self.text[self.start..self.end]
+    }
+}
+
+/// The spans of every capture group in a single match of a regexp. Group 0
+/// is the whole match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Captures<'t> {
+    text: &'t str,
+    slots: Vec<Option<usize>>,
+    names: Arc<HashMap<String, usize>>
+}
+
+impl<'t> Captures<'t> {
+    /// Returns the match of group `i`, or `None` if the group did not
+    /// participate in the match or does not exist.
+    pub fn get(&self, i: usize) -> Option<Match<'t>> {
+        match (self.slots.get(2 * i), self.slots.get(2 * i + 1)) {
+            (Some(&Some(start)), Some(&Some(end)))
+                => Some(Match::new(self.text, start, end)),
+            _ => None
+        }
+    }
+
+    /// Returns the match of the group called `name`, or `None` if the group
+    /// did not participate in the match or does not exist.
+    pub fn name(&self, name: &str) -> Option<Match<'t>> {
+        self.names.get(name).and_then(|&i| self.get(i))
+    }
+
+    /// The number of groups, including group 0.
+    pub fn len(&self) -> usize {
+        self.slots.len() / 2
+    }
+
+    /// Always false, since group 0 is always present.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'t> Index<usize> for Captures<'t> {
+    type Output = str;
+
+    /// Returns the text matched by group `i`.
+    ///
+    /// Panics if the group did not participate in the match.
+    fn index(&self, i: usize) -> &str {
+        match self.get(i) {
+            Some(m) => m.as_str(),
+            None => panic!("no match for capture group {}", i)
+        }
+    }
+}
+
+impl<'t, 'n> Index<&'n str> for Captures<'t> {
+    type Output = str;
+
+    /// Returns the text matched by the group called `name`.
+    ///
+    /// Panics if there is no such group or it did not participate in the
+    /// match.
+    fn index(&self, name: &'n str) -> &str {
+        match self.name(name) {
+            Some(m) => m.as_str(),
+            None => panic!("no match for capture group {:?}", name)
+        }
+    }
+}
+
+/// Maps the name of every named capture group in `regexp` to its index.
+fn capture_names(regexp: &Regexp) -> HashMap<String, usize> {
+    fn collect(regexp: &Regexp, names: &mut HashMap<String, usize>) {
+        use create::Regexp::*;
+        match *regexp {
+            Empty | Char(_) | Class { .. } | Perl { .. } | Property { .. }
+                | AnyChar { .. } | Assertion(_) | Backreference(_) => (),
+            Concatenation(ref regexps) | Alternation(ref regexps) => {
+                for regexp in regexps {
+                    collect(regexp, names);
+                }
+            },
+            Capture { index, ref name, ref inner } => {
+                if let Some(ref name) = *name {
+                    names.insert(name.clone(), index);
+                }
+                collect(inner, names);
+            },
+            Optional(ref inner, _) | Repeated(ref inner, _)
+                | OptionalRepeated(ref inner, _) | Repeat { ref inner, .. }
+                | Atomic(ref inner) | Lookahead { ref inner, .. }
+                | Lookbehind { ref inner, .. }
+                | CaseInsensitive { ref inner, .. } => collect(inner, names)
+        }
+    }
+
+    let mut names = HashMap::new();
+    collect(regexp, &mut names);
+    names
+}
+
+/// Runs `insts` over `input` with the Pike VM, or with the backtracker if
+/// the program has backreferences, which the Pike VM cannot match.
+fn execute(insts: &[Inst], captures_len: usize, input: &str,
+           anchoring: Anchoring, backtracking: bool)
+           -> Option<Vec<Option<usize>>> {
+    if backtracking {
+        backtrack::backtrack(insts, captures_len, input, anchoring)
+    } else {
+        thompson_nfa::thompson_vm(insts, captures_len, input, anchoring)
+    }
+}
+
+/// A compiled regular expression. The pattern is parsed and compiled once,
+/// when the `Regex` is built, and every matching method reuses the program.
+///
+/// Matching takes time linear in the length of the input, with two
+/// exceptions. Patterns with backreferences are run by backtracking, which
+/// can take exponential time. And an atomic group, possessive quantifier
+/// or lookaround runs a search of its own for each position it is reached
+/// at, each of which can scan to the end of the input, so that `(?>a+)b`
+/// and `(?=a*b)` take time quadratic in the length of a run of `a`s. Each
+/// of those searches runs once per position, however deeply the groups
+/// are nested.
+#[derive(Debug)]
+pub struct Regex {
+    pattern: String,
+    regexp: Regexp,
+    insts: Vec<Inst>,
+    captures_len: usize,
+    capture_names: Arc<HashMap<String, usize>>,
+    anchoring: Anchoring,
+    backtracking: bool
+}
+
+impl Regex {
+    /// Parses and compiles `pattern` with the default options.
+    pub fn new(pattern: &str) -> Result<Regex, RegexpError> {
+        RegexBuilder::new(pattern).build()
+    }
+
+    /// The pattern this regex was built from.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
 
+    /// The parsed form of the pattern.
+    pub fn regexp(&self) -> &Regexp {
+        &self.regexp
+    }
+
+    /// The compiled instruction program.
+    pub fn insts(&self) -> &[Inst] {
+        &self.insts
+    }
+
+    /// The number of capture groups, including group 0.
+    pub fn captures_len(&self) -> usize {
+        self.captures_len
+    }
+
+    /// The index of the capture group called `name`, if there is one.
+    pub fn capture_index(&self, name: &str) -> Option<usize> {
+        self.capture_names.get(name).cloned()
+    }
+
+    /// Where in the input matches are allowed to begin.
+    pub fn anchoring(&self) -> Anchoring {
+        self.anchoring
+    }
+
+    /// Whether matching uses the backtracker rather than the Pike VM, which
+    /// is only the case for patterns with backreferences.
+    pub fn is_backtracking(&self) -> bool {
+        self.backtracking
+    }
+
+    /// Returns true if this regex matches `input`.
+    pub fn is_match(&self, input: &str) -> bool {
+        self.find(input).is_some()
+    }
+
+    /// Returns the leftmost match in `input`.
+    pub fn find<'t>(&self, input: &'t str) -> Option<Match<'t>> {
+        self.captures(input).and_then(|caps| caps.get(0))
+    }
+
+    /// Returns the capture groups of the leftmost match in `input`.
+    pub fn captures<'t>(&self, input: &'t str) -> Option<Captures<'t>> {
+        execute(&self.insts, self.captures_len, input, self.anchoring,
+                self.backtracking)
+            .map(|slots| Captures { text: input, slots,
+                                    names: self.capture_names.clone() })
+    }
+}
+
+/// Configures and builds a `Regex`.
+#[derive(Debug, Clone)]
+pub struct RegexBuilder {
+    pattern: String,
+    anchoring: Anchoring,
+    flags: Flags
+}
+
+impl RegexBuilder {
+    /// Starts a builder for `pattern` with the default options: matches may
+    /// begin anywhere in the input.
+    pub fn new(pattern: &str) -> RegexBuilder {
+        RegexBuilder { pattern: pattern.to_string(),
+                       anchoring: Anchoring::Unanchored,
+                       flags: Flags::default() }
+    }
+
+    /// Sets where in the input matches are allowed to begin.
+    pub fn anchoring(&mut self, anchoring: Anchoring) -> &mut RegexBuilder {
+        self.anchoring = anchoring;
+        self
+    }
+
+    /// Sets whether `.` also matches `\n`. Off by default.
+    pub fn dot_matches_new_line(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.dot_matches_new_line = yes;
+        self
+    }
+
+    /// Sets whether `^` and `$` also match at the start and end of each
+    /// line. Off by default.
+    pub fn multi_line(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.multi_line = yes;
+        self
+    }
+
+    /// Sets whether letters match regardless of case, by Unicode simple
+    /// case folding. Off by default.
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.case_insensitive = yes;
+        self
+    }
+
+    /// Sets whether whitespace in the pattern is ignored and `#` starts a
+    /// comment running to the end of the line. Off by default.
+    pub fn ignore_whitespace(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.ignore_whitespace = yes;
+        self
+    }
+
+    /// Sets whether predefined classes such as `\d` cover all of Unicode
+    /// rather than only ASCII. On by default.
+    pub fn unicode(&mut self, yes: bool) -> &mut RegexBuilder {
+        self.flags.unicode = yes;
+        self
+    }
+
+    /// Parses and compiles the pattern.
+    pub fn build(&self) -> Result<Regex, RegexpError> {
+        let regexp = Regexp::from_string_with_flags(&self.pattern,
+                                                    self.flags)?;
+        let insts = thompson_nfa::compile_regexp(&regexp);
+        let captures_len = regexp.captures_len();
+        let capture_names = Arc::new(capture_names(&regexp));
+        let backtracking = backtrack::needs_backtracking(&insts);
+        Result::Ok(Regex { pattern: self.pattern.clone(),
+                           regexp,
+                           insts,
+                           captures_len,
+                           capture_names,
+                           anchoring: self.anchoring,
+                           backtracking })
+    }
+}
+
+/// Returns true if `regexp` matches anywhere in `input`.
 pub fn is_match(regexp: &Regexp, input: &str) -> bool {
+    is_match_with(regexp, input, Anchoring::Unanchored)
+}
+
+/// Returns true if `regexp` matches `input`, with the match start restricted
+/// according to `anchoring`.
+pub fn is_match_with(regexp: &Regexp, input: &str, anchoring: Anchoring)
+                     -> bool {
+    find_with(regexp, input, anchoring).is_some()
+}
+
+/// Returns the leftmost match of `regexp` in `input`.
+pub fn find<'t>(regexp: &Regexp, input: &'t str) -> Option<Match<'t>> {
+    find_with(regexp, input, Anchoring::Unanchored)
+}
+
+/// Returns the leftmost match of `regexp` in `input`, with the match start
+/// restricted according to `anchoring`.
+pub fn find_with<'t>(regexp: &Regexp, input: &'t str, anchoring: Anchoring)
+                     -> Option<Match<'t>> {
+    captures_with(regexp, input, anchoring).and_then(|caps| caps.get(0))
+}
+
+/// Returns the capture groups of the leftmost match of `regexp` in `input`.
+pub fn captures<'t>(regexp: &Regexp, input: &'t str) -> Option<Captures<'t>> {
+    captures_with(regexp, input, Anchoring::Unanchored)
+}
+
+/// Returns the capture groups of the leftmost match of `regexp` in `input`,
+/// with the match start restricted according to `anchoring`.
+pub fn captures_with<'t>(regexp: &Regexp, input: &'t str,
+                         anchoring: Anchoring) -> Option<Captures<'t>> {
     let insts = thompson_nfa::compile_regexp(regexp);
-    let result = thompson_nfa::thompson_vm(&insts, input);
-    result
+    execute(&insts, regexp.captures_len(), input, anchoring,
+            backtrack::needs_backtracking(&insts))
+        .map(|slots| Captures { text: input, slots,
+                                names: Arc::new(capture_names(regexp)) })
 }
@@ -1,92 +1,514 @@
-use create::Regexp;
+use std::collections::{HashMap, HashSet};
+
+use class::{is_word_char, unicode_property, CharClass};
+use create::{Assertion, Greed, Regexp};
 
 #[derive(Debug, PartialEq)]
 pub enum Inst {
     Char(char),
     Match,
     Jump(usize),
-    Split(usize, usize)
+    /// Continue at both pcs, preferring the first.
+    Split(usize, usize),
+    /// Record the current input position in capture slot `n`. Group `i`
+    /// starts at slot `2 * i` and ends at slot `2 * i + 1`.
+    Save(usize),
+    /// Match any one char in the class.
+    Class(CharClass),
+    /// Match any one char, except `\n` unless `newline` is set.
+    AnyChar { newline: bool },
+    /// Continue only if the assertion holds at the current position.
+    Assert(Assertion),
+    /// Run the sub-program that starts at the next pc and ends with its own
+    /// `Match`, then continue at the given pc from the end of the sub-
+    /// program's preferred match only. Other ways of matching the
+    /// sub-program are never tried. The sub-program's match from each
+    /// position is found once per search and then reused.
+    Atomic(usize),
+    /// Run the sub-program that starts at the next pc and ends with its own
+    /// `Match` from the current position, then continue at `next` if it
+    /// matched, or if it did not and the lookahead is `negated`. As for
+    /// `Atomic`, the sub-program's match for each position is reused.
+    Lookahead { negated: bool, next: usize },
+    /// Like `Lookahead`, but the sub-program must match ending at the
+    /// current position, starting from between `min_len` and `max_len`
+    /// chars before it.
+    Lookbehind { negated: bool, next: usize, min_len: usize, max_len: usize },
+    /// Match the text last matched by capture group `n`. Only `backtrack`
+    /// can run programs with this instruction; in `thompson_vm` it never
+    /// matches.
+    Backreference(usize),
+    /// Like `Backreference`, but matching regardless of case as for
+    /// `CharClass::case_fold`.
+    FoldedBackreference { group: usize, unicode: bool }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Thread {
-    pub saw_char: bool,
-    pub pc: usize
+    pub pc: usize,
+    /// Byte offset in the input at which this thread's match attempt began.
+    pub start: usize,
+    /// Capture slots recorded so far by `Save` instructions.
+    pub saves: Vec<Option<usize>>,
+    /// Set while the thread waits for the input to reach the end of an
+    /// atomic sub-program's match, after which it continues at `pc`.
+    pub resume_at: Option<usize>
+}
+
+/// Where in the input a match is allowed to begin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchoring {
+    /// The match may begin at any position, as if the program were prefixed
+    /// with `.*?`.
+    Unanchored,
+    /// The match must begin at the start of the input.
+    Start
+}
+
+/// The end of a sub-program's match and the capture slots it set.
+type SubMatch = (usize, Vec<Option<usize>>);
+
+/// One search of `input` by the program `insts`.
+struct Search<'a> {
+    insts: &'a [Inst],
+    input: &'a str,
+    /// The preferred matches of the atomic and lookaround sub-programs run
+    /// so far, or `None` for those that did not match, by the pc of the
+    /// instruction that runs them and the byte offset it ran them for.
+    /// Threads reaching the same pc at the same position in any nested run
+    /// reuse them.
+    sub_matches: HashMap<(usize, usize), Option<SubMatch>>
 }
 
+/// The threads alive at one input position, in priority order. `seen` marks
+/// every pc already reached at this position, so each pc is followed once.
+/// `waiting` holds the pc and resume position of every thread waiting on an
+/// atomic sub-program, so that each is kept once too.
+struct ThreadList {
+    threads: Vec<Thread>,
+    seen: Vec<bool>,
+    waiting: HashSet<(usize, usize)>
+}
+
+impl ThreadList {
+    fn new(num_insts: usize) -> ThreadList {
+        ThreadList { threads: Vec::new(), seen: vec![false; num_insts],
+                     waiting: HashSet::new() }
+    }
+
+    fn clear(&mut self) {
+        self.threads.clear();
+        for seen in self.seen.iter_mut() { *seen = false; }
+        self.waiting.clear();
+    }
+
+    /// Adds `thread`, which waits to resume at `resume_at`, unless a thread
+    /// before it already waits to resume at the same pc and position. That
+    /// one would reach the pc first, so `thread` could never get further.
+    fn add_waiting(&mut self, thread: Thread, resume_at: usize) {
+        if self.waiting.insert((thread.pc, resume_at)) {
+            self.threads.push(Thread { resume_at: Some(resume_at),
+                                       ..thread });
+        }
+    }
+
+    /// Adds `thread` and every thread reachable from it without consuming
+    /// input at byte offset `pos`, which follows `prev_char`. Only threads
+    /// waiting to consume a char, at `Match`, or waiting on an atomic
+    /// sub-program, are kept.
+    fn add(&mut self, search: &mut Search, thread: Thread, pos: usize,
+           prev_char: Option<char>) {
+        if self.seen[thread.pc] { return; }
+        self.seen[thread.pc] = true;
 
-pub fn thompson_vm(insts: &Vec<Inst>, input: &str) -> bool {
-    fn addthread(list: &mut Vec<Thread>, thread: Thread) {
-        let mut contains = false;
-        for elem in list.iter() {
-            if thread == *elem { contains = true; break; }
+        use self::Inst::*;
+        match search.insts[thread.pc] {
+            Char(_) | Class(_) | AnyChar { .. } | Backreference(_)
+                | FoldedBackreference { .. } | Match => {
+                self.threads.push(thread)
+            },
+            Jump(jump_pc) => {
+                self.add(search, Thread { pc: jump_pc, ..thread }, pos,
+                         prev_char);
+            },
+            Split(s1_pc, s2_pc) => {
+                let s2_thread = Thread { pc: s2_pc,
+                                         saves: thread.saves.clone(),
+                                         ..thread };
+                self.add(search, Thread { pc: s1_pc, ..thread }, pos,
+                         prev_char);
+                self.add(search, s2_thread, pos, prev_char);
+            },
+            Save(slot) => {
+                let mut saves = thread.saves;
+                saves[slot] = Some(pos);
+                self.add(search, Thread { pc: thread.pc + 1,
+                                          saves,
+                                          ..thread }, pos, prev_char);
+            },
+            Assert(assertion) => {
+                if assertion_holds(assertion, search.input, pos, prev_char) {
+                    self.add(search, Thread { pc: thread.pc + 1, ..thread },
+                             pos, prev_char);
+                }
+            },
+            Lookahead { negated, next } | Lookbehind { negated, next, .. } => {
+                let sub_match = search.sub_match(thread.pc, pos,
+                                                 thread.saves.len());
+                let saves = match (sub_match, negated) {
+                    (Some((_, set)), false) => merge_saves(thread.saves, set),
+                    (None, true) => thread.saves,
+                    _ => return
+                };
+                self.add(search, Thread { pc: next, saves, ..thread }, pos,
+                         prev_char);
+            },
+            Atomic(next_pc) => {
+                let sub_match = search.sub_match(thread.pc, pos,
+                                                 thread.saves.len());
+                if let Some((end, set)) = sub_match {
+                    let saves = merge_saves(thread.saves, set);
+                    let thread = Thread { pc: next_pc, saves, ..thread };
+                    if end == pos {
+                        self.add(search, thread, pos, prev_char);
+                    } else {
+                        self.add_waiting(thread, end);
+                    }
+                }
+            }
         }
-        if !contains { list.push(thread); }
     }
+}
+
+/// Returns true if `inst`, which must consume a char, accepts `c`.
+pub fn char_matches(inst: &Inst, c: char) -> bool {
+    match *inst {
+        Inst::Char(expected) => c == expected,
+        Inst::Class(ref class) => class.contains(c),
+        Inst::AnyChar { newline } => newline || c != '\n',
+        _ => false
+    }
+}
+
+/// The earliest byte offset of `input` at which a lookbehind of at most
+/// `max_len` chars can start to end at byte offset `pos`.
+pub fn lookbehind_start(input: &str, pos: usize, max_len: usize) -> usize {
+    input[..pos].char_indices().rev().take(max_len).last()
+        .map_or(pos, |(i, _)| i)
+}
+
+/// Returns `saves` with each slot that a sub-program `set` overwritten.
+fn merge_saves(saves: Vec<Option<usize>>, set: Vec<Option<usize>>)
+               -> Vec<Option<usize>> {
+    saves.into_iter().zip(set).map(|(old, new)| new.or(old)).collect()
+}
+
+/// Returns true if `assertion` holds at byte offset `pos` of `input`, where
+/// `prev_char` is the char just before `pos`. Lines end at `\n` or `\r\n`,
+/// and neither line assertion holds between the `\r` and `\n` of a `\r\n`.
+pub fn assertion_holds(assertion: Assertion, input: &str, pos: usize,
+                       prev_char: Option<char>) -> bool {
+    let mut following = input[pos..].chars();
+    let next_char = following.next();
+    let word_boundary = |unicode| {
+        let is_word = |c: Option<char>| {
+            c.is_some_and(|c| is_word_char(c, unicode))
+        };
+        is_word(prev_char) != is_word(next_char)
+    };
+    match assertion {
+        Assertion::StartText => pos == 0,
+        Assertion::EndText => pos == input.len(),
+        Assertion::StartLine => prev_char.is_none() || prev_char == Some('\n'),
+        Assertion::EndLine => match next_char {
+            None => true,
+            Some('\n') => prev_char != Some('\r'),
+            Some('\r') => following.next() == Some('\n'),
+            Some(_) => false
+        },
+        Assertion::WordBoundary { unicode } => word_boundary(unicode),
+        Assertion::NotWordBoundary { unicode } => !word_boundary(unicode)
+    }
+}
+
+/// The number of capture slots to give threads running `insts` for
+/// `captures_len` groups as counted by `Regexp::captures_len`. This is
+/// never fewer than the `Save` instructions in `insts` use, whatever
+/// `captures_len` is.
+pub fn num_slots(insts: &[Inst], captures_len: usize) -> usize {
+    insts.iter()
+        .filter_map(|inst| match *inst {
+            Inst::Save(slot) => Some(slot + 1),
+            _ => None
+        })
+        .fold(2 * captures_len.max(1), usize::max)
+}
 
-    let mut cur_stack: Vec<Thread> = Vec::new();
-    let mut new_stack: Vec<Thread> = Vec::new();
+/// Runs `insts` over `input` as a Pike VM and returns the capture slots of
+/// the leftmost match. Slots 0 and 1 hold the byte span of the whole match,
+/// followed by a start/end pair for each capture group, with slots for at
+/// least `captures_len - 1` groups as counted by `Regexp::captures_len`.
+///
+/// Threads are kept in priority order, so among matches starting at the
+/// same position the one preferred by the program's `Split`s wins, and
+/// capture groups report what that preferred match went through.
+pub fn thompson_vm(insts: &[Inst], captures_len: usize, input: &str,
+                   anchoring: Anchoring) -> Option<Vec<Option<usize>>> {
+    let saves = vec![None; num_slots(insts, captures_len)];
+    let mut search = Search { insts, input, sub_matches: HashMap::new() };
+    let matched = search.run(0, 0, anchoring, None, saves);
+    matched.map(|(start, end, mut saves)| {
+        saves[0] = Some(start);
+        saves[1] = Some(end);
+        saves
+    })
+}
 
-    addthread(&mut cur_stack, Thread { saw_char: false, pc: 0});
+impl<'a> Search<'a> {
+    /// Returns the preferred match of the sub-program of the `Atomic`,
+    /// `Lookahead` or `Lookbehind` at `pc` for byte offset `pos`, ignoring
+    /// whether a lookaround is negated, with the capture slots it set out
+    /// of `num_slots`. The sub-program runs for each position at most once
+    /// per search.
+    fn sub_match(&mut self, pc: usize, pos: usize, num_slots: usize)
+                 -> Option<SubMatch> {
+        if let Some(sub_match) = self.sub_matches.get(&(pc, pos)) {
+            return sub_match.clone();
+        }
+        let saves = vec![None; num_slots];
+        let sub_match = match self.insts[pc] {
+            Inst::Lookbehind { max_len, .. } => {
+                let start = lookbehind_start(self.input, pos, max_len);
+                self.run(pc + 1, start, Anchoring::Unanchored, Some(pos),
+                         saves)
+            },
+            _ => self.run(pc + 1, pos, Anchoring::Start, None, saves)
+        }.map(|(_, end, set)| (end, set));
+        self.sub_matches.insert((pc, pos), sub_match.clone());
+        sub_match
+    }
 
-    for (_, cur_char) in input.chars().enumerate() {
-        let mut i = 0;
+    /// Runs the program starting at `start_pc` from byte offset `start_pos`
+    /// of `input`, with every thread starting from the capture slots
+    /// `saves`. Returns the start and end of the preferred match and its
+    /// capture slots. If `must_end_at` is set, only matches ending there
+    /// count.
+    fn run(&mut self, start_pc: usize, start_pos: usize,
+           anchoring: Anchoring, must_end_at: Option<usize>,
+           saves: Vec<Option<usize>>)
+           -> Option<(usize, usize, Vec<Option<usize>>)> {
+        let (insts, input) = (self.insts, self.input);
+        let mut cur_stack = ThreadList::new(insts.len());
+        let mut new_stack = ThreadList::new(insts.len());
+        let mut matched = None;
+        let mut pos = start_pos;
+        // The char before `pos`, kept up to date as the input is consumed.
+        let mut prev_char = input[..start_pos].chars().next_back();
 
         loop {
-            if i >= cur_stack.len() { break; }
-
-            let thread_pc: usize = cur_stack[i].pc;
-            let thread_saw_char: bool = cur_stack[i].saw_char;
-            let inst = &insts[thread_pc];
-
-            use Inst::*;
-            match *inst {
-                Char(c) => {
-                    if thread_saw_char {
-                        addthread(&mut new_stack, Thread { saw_char: false,
-                                                      pc: thread_pc });
+            // An unanchored search starts a fresh attempt at every position,
+            // which is what a leading `.*?` would do without recompiling. The
+            // new attempt has the lowest priority, and none are started once a
+            // match has been found.
+            if matched.is_none()
+                && (pos == start_pos || anchoring == Anchoring::Unanchored) {
+                cur_stack.add(self, Thread { pc: start_pc,
+                                             start: pos,
+                                             saves: saves.clone(),
+                                             resume_at: None },
+                              pos, prev_char);
+            }
+            // Seeds can die in an assertion, so an empty list only ends an
+            // unanchored search once no more seeds will be started.
+            if cur_stack.threads.is_empty()
+                && (matched.is_some() || anchoring == Anchoring::Start) {
+                break;
+            }
+
+            let cur_char = input[pos..].chars().next();
+            let next_pos = pos + cur_char.map_or(0, |c| c.len_utf8());
+            for thread in cur_stack.threads.drain(..) {
+                // A thread waiting on an atomic sub-program keeps its place in
+                // the priority order until the input catches up with it.
+                if let Some(resume_at) = thread.resume_at {
+                    if resume_at == next_pos {
+                        new_stack.add(self, Thread { resume_at: None,
+                                                     ..thread },
+                                      next_pos, cur_char);
                     } else {
-                        if c == cur_char {
-                            addthread(&mut cur_stack, Thread { saw_char: true,
-                                                          pc: thread_pc + 1});
+                        new_stack.add_waiting(thread, resume_at);
+                    }
+                    continue;
+                }
+
+                match insts[thread.pc] {
+                    Inst::Match => {
+                        if must_end_at.is_some_and(|end| end != pos) {
+                            continue;
+                        }
+                        // Lower priority threads can no longer win.
+                        matched = Some((thread.start, pos, thread.saves));
+                        break;
+                    },
+                    ref inst => {
+                        if cur_char.is_some_and(|c| char_matches(inst, c)) {
+                            new_stack.add(self, Thread { pc: thread.pc + 1,
+                                                         ..thread },
+                                          next_pos, cur_char);
                         }
                     }
-                },
-                Match => return true,
-                Jump(jump_pc) => {
-                    addthread(&mut cur_stack,
-                              Thread { saw_char: thread_saw_char,
-                                       pc: jump_pc });
-                },
-                Split(s1_pc, s2_pc) => {
-                    addthread(&mut cur_stack,
-                              Thread { saw_char: thread_saw_char,
-                                       pc: s1_pc});
-                    addthread(&mut cur_stack,
-                              Thread { saw_char: thread_saw_char,
-                                       pc: s2_pc});
                 }
             }
-            
-            i += 1
+
+            if cur_char.is_none() || must_end_at == Some(pos) { break; }
+            pos = next_pos;
+            prev_char = cur_char;
+            ::std::mem::swap(&mut cur_stack, &mut new_stack);
+            new_stack.clear();
         }
-        cur_stack = new_stack;
-        new_stack = Vec::new();
+        matched
     }
-    false
 }
 
 pub fn compile_regexp(regexp: &Regexp) -> Vec<Inst> {
-    let mut insts = compile_regexp_offset(&regexp, 0);
+    let mut insts = compile_regexp_offset(regexp, 0);
+    insts.push(Inst::Match);
+    insts
+}
+
+/// A `Split` between repeating again at `repeat_pc` and moving on to
+/// `exit_pc`, preferring whichever `greed` asks for.
+/// Possessive quantifiers are compiled as greedy ones inside an atomic
+/// sub-program, so they prefer repeating here too.
+fn split(greed: Greed, repeat_pc: usize, exit_pc: usize) -> Inst {
+    match greed {
+        Greed::Greedy | Greed::Possessive => Inst::Split(repeat_pc, exit_pc),
+        Greed::Lazy => Inst::Split(exit_pc, repeat_pc)
+    }
+}
+
+/// Wraps `body`, compiled to start at `offset + 1`, in a sub-program
+/// starting at `offset` with the instruction `head` makes from the pc after
+/// the sub-program.
+fn sub_program_insts<F>(offset: usize, mut body: Vec<Inst>, head: F)
+                        -> Vec<Inst>
+    where F: FnOnce(usize) -> Inst {
+    let mut insts = vec![head(offset + body.len() + 2)];
+    insts.append(&mut body);
     insts.push(Inst::Match);
     insts
 }
 
 fn compile_regexp_offset(regexp: &Regexp, offset: usize) -> Vec<Inst> {
+    use create::Regexp::*;
+    match *regexp {
+        Optional(_, Greed::Possessive)
+            | Repeated(_, Greed::Possessive)
+            | OptionalRepeated(_, Greed::Possessive)
+            | Repeat { greed: Greed::Possessive, .. } => {
+                // `a*+` matches like `(?>a*)`.
+                sub_program_insts(offset, compile_node(regexp, offset + 1),
+                                  Inst::Atomic)
+            },
+        _ => compile_node(regexp, offset)
+    }
+}
+
+/// The class a char, class, property or predefined class compiles to, or
+/// `None` for any other node.
+fn node_class(regexp: &Regexp) -> Option<CharClass> {
+    use create::Regexp::*;
+    let (class, negated) = match *regexp {
+        Class { ref class, negated } => (class.clone(), negated),
+        // A property the parser would have rejected matches nothing.
+        Property { ref name, negated }
+            => (unicode_property(name).unwrap_or_else(CharClass::empty),
+                negated),
+        Perl { class, negated, unicode } => (class.to_class(unicode), negated),
+        CaseInsensitive { ref inner, unicode } => {
+            // Folding turns chars, classes and properties into a class
+            // here, so that matching never has to fold.
+            let (class, negated) = match **inner {
+                Char(c) => (CharClass::new(vec![(c, c)]), false),
+                Class { ref class, negated } => (class.clone(), negated),
+                Property { ref name, negated } => {
+                    (unicode_property(name).unwrap_or_else(CharClass::empty),
+                     negated)
+                },
+                _ => return None
+            };
+            (class.case_fold(unicode), negated)
+        },
+        _ => return None
+    };
+    Some(if negated { class.negate() } else { class })
+}
+
+/// The most `program_cost` allows for a pattern. Programs this size take
+/// tens of megabytes.
+pub const MAX_PROGRAM_COST: usize = 1 << 20;
+
+/// Roughly how much memory `compile_regexp` would take for `regexp`: the
+/// number of instructions it emits plus the number of ranges in their
+/// classes, or `None` if that overflows. This is worked out without
+/// compiling, so that patterns too large to compile can be turned down.
+pub fn program_cost(regexp: &Regexp) -> Option<usize> {
+    use create::Regexp::*;
+    if let Some(class) = node_class(regexp) {
+        return Some(1 + class.ranges().len());
+    }
+    // Possessive quantifiers are wrapped in an atomic sub-program.
+    let atomic = match *regexp {
+        Optional(_, Greed::Possessive)
+            | Repeated(_, Greed::Possessive)
+            | OptionalRepeated(_, Greed::Possessive)
+            | Repeat { greed: Greed::Possessive, .. } => 2,
+        _ => 0
+    };
+    let cost = match *regexp {
+        Empty => 0,
+        Char(_) | AnyChar { .. } | Assertion(_) | Backreference(_) => 1,
+        Concatenation(ref regexps) | Alternation(ref regexps) => {
+            // Every alternative but the last adds a `Split` and a `Jump`.
+            let glue = match *regexp {
+                Alternation(_) => 2 * (regexps.len() - 1),
+                _ => 0
+            };
+            regexps.iter().try_fold(glue, |cost, regexp| {
+                cost.checked_add(program_cost(regexp)?)
+            })?
+        },
+        Optional(ref inner, _) | Repeated(ref inner, _)
+            => program_cost(inner)?.checked_add(1)?,
+        OptionalRepeated(ref inner, _) | Capture { ref inner, .. }
+            | Atomic(ref inner) | Lookahead { ref inner, .. }
+            | Lookbehind { ref inner, .. }
+            => program_cost(inner)?.checked_add(2)?,
+        Repeat { ref inner, min, max, .. } => {
+            let inner = program_cost(inner)?;
+            let (copies, rest) = match max {
+                // `x{n,}` repeats its last copy with one more `Split`.
+                None if min > 0 => (min, 1),
+                None => (0, inner.checked_add(2)?),
+                Some(max) => (min, inner.checked_add(1)?
+                              .checked_mul(max.saturating_sub(min) as usize)?)
+            };
+            inner.checked_mul(copies as usize)?.checked_add(rest)?
+        },
+        CaseInsensitive { ref inner, .. } => program_cost(inner)?,
+        Class { .. } | Property { .. } | Perl { .. } => unreachable!()
+    };
+    cost.checked_add(atomic)
+}
+
+/// Compiles `regexp` to start at `offset`, treating a possessive quantifier
+/// at its root as greedy.
+fn compile_node(regexp: &Regexp, offset: usize) -> Vec<Inst> {
     let mut insts = Vec::new();
     use create::Regexp::*;
     use self::Inst::{Jump,Split};
     match *regexp {
+        Empty => (),
         Char(c) => {
             let char_inst = Inst::Char(c);
             insts.push(char_inst);
@@ -94,59 +516,161 @@ fn compile_regexp_offset(regexp: &Regexp, offset: usize) -> Vec<Inst> {
         Concatenation(ref regexps) => {
             let mut num_insts = 0;
             for sub_regexp in regexps {
-                let mut sub_insts = compile_regexp_offset(&sub_regexp,
+                let mut sub_insts = compile_regexp_offset(sub_regexp,
                                                           offset + num_insts);
                 num_insts += sub_insts.len();
                 insts.append(&mut sub_insts);
             }
         },
         Alternation(ref regexps) => {
-            let num_alternatives = regexps.len();
-            let mut alternatives_insts: Vec<Vec<Inst>> = Vec::new();
-            for sub_regexp in regexps {
-                let sub_insts = compile_regexp_offset(&sub_regexp, offset);
-                alternatives_insts.push(sub_insts);
-            }
-            let total_len = alternatives_insts.iter()
-                .fold(0, |sum,x| sum + x.len())
-                + alternatives_insts.len() - 1; // + alternates_insts.len() - 1
-                                                // accounts for the Jumps we add
-            for (i, sub_insts) in alternatives_insts.iter_mut().enumerate() {
-                // Add a jump instruction to all but the last alternative
-                if i < num_alternatives - 1 {
-                    let jump_to_end = Jump(total_len + offset);
-                    sub_insts.push(jump_to_end);
+            // Each alternative but the last is entered through a Split that
+            // prefers it over the alternatives after it, and ends with a
+            // Jump past the last alternative.
+            let mut jump_indices = Vec::new();
+            for (i, sub_regexp) in regexps.iter().enumerate() {
+                if i == regexps.len() - 1 {
+                    let mut sub_insts =
+                        compile_regexp_offset(sub_regexp, offset + insts.len());
+                    insts.append(&mut sub_insts);
+                    break;
                 }
-                insts.append(sub_insts);
+                let split_index = insts.len();
+                insts.push(Split(0, 0));
+                let mut sub_insts =
+                    compile_regexp_offset(sub_regexp, offset + insts.len());
+                insts.append(&mut sub_insts);
+                jump_indices.push(insts.len());
+                insts.push(Jump(0));
+                insts[split_index] = Split(offset + split_index + 1,
+                                           offset + insts.len());
+            }
+            let end = offset + insts.len();
+            for i in jump_indices {
+                insts[i] = Jump(end);
             }
         },
-        Optional(ref inner_regexp) => {
-            let mut inner_insts = compile_regexp_offset(&*inner_regexp,
+        Optional(ref inner_regexp, greed) => {
+            let mut inner_insts = compile_regexp_offset(inner_regexp,
                                                         offset + 1);
-            let split_inst = Split(offset + 1, offset + inner_insts.len() + 1);
+            let split_inst = split(greed, offset + 1,
+                                   offset + inner_insts.len() + 1);
             insts.push(split_inst);
             insts.append(&mut inner_insts);
         },
-        Repeated(ref inner_regexp) => {
-            let mut inner_insts = compile_regexp_offset(&*inner_regexp,
+        Repeated(ref inner_regexp, greed) => {
+            let mut inner_insts = compile_regexp_offset(inner_regexp,
                                                         offset);
-            let split_inst = Split(offset, offset + inner_insts.len() + 1);
+            let split_inst = split(greed, offset,
+                                   offset + inner_insts.len() + 1);
             insts.append(&mut inner_insts);
             insts.push(split_inst);
         },
-        OptionalRepeated(ref inner_regexp) => {
-            let mut inner_insts = compile_regexp_offset(&*inner_regexp,
-                                                        offset + 1);
-            let split_inst = Split(offset + 1, offset + inner_insts.len() + 2);
-            let jump_inst = Jump(offset);
-            insts.push(split_inst);
+        Class { .. } | Property { .. } | Perl { .. } => {
+            insts.push(Inst::Class(node_class(regexp).unwrap()));
+        },
+        AnyChar { newline } => insts.push(Inst::AnyChar { newline }),
+        Backreference(index) => insts.push(Inst::Backreference(index)),
+        CaseInsensitive { ref inner, unicode } => match node_class(regexp) {
+            Some(class) => insts.push(Inst::Class(class)),
+            None => match **inner {
+                Backreference(group) => {
+                    insts.push(Inst::FoldedBackreference { group, unicode });
+                },
+                _ => return compile_regexp_offset(inner, offset)
+            }
+        },
+        Assertion(assertion) => insts.push(Inst::Assert(assertion)),
+        Repeat { ref inner, min, max, greed } => {
+            // `x{n,}` is compiled as `x{n-1}x+`, and `x{0,}` as `x*`.
+            let copies = match max {
+                None => min.saturating_sub(1),
+                Some(_) => min
+            };
+            for _ in 0..copies {
+                let mut inner_insts =
+                    compile_regexp_offset(inner, offset + insts.len());
+                insts.append(&mut inner_insts);
+            }
+            match max {
+                None if min == 0 => insts = star_insts(inner, greed, offset),
+                None => {
+                    let body_pc = offset + insts.len();
+                    let mut inner_insts = compile_regexp_offset(inner,
+                                                                body_pc);
+                    let exit_pc = body_pc + inner_insts.len() + 1;
+                    insts.append(&mut inner_insts);
+                    insts.push(split(greed, body_pc, exit_pc));
+                },
+                Some(max) => {
+                    // Each optional copy may instead skip to the end.
+                    let mut split_indices = Vec::new();
+                    for _ in min..max {
+                        split_indices.push(insts.len());
+                        insts.push(Split(0, 0));
+                        let mut inner_insts =
+                            compile_regexp_offset(inner, offset + insts.len());
+                        insts.append(&mut inner_insts);
+                    }
+                    let end = offset + insts.len();
+                    for i in split_indices {
+                        insts[i] = split(greed, offset + i + 1, end);
+                    }
+                }
+            }
+        },
+        Atomic(ref inner) => {
+            let inner_insts = compile_regexp_offset(inner, offset + 1);
+            insts = sub_program_insts(offset, inner_insts, Inst::Atomic);
+        },
+        Lookahead { ref inner, negated } => {
+            let inner_insts = compile_regexp_offset(inner, offset + 1);
+            insts = sub_program_insts(offset, inner_insts, |next| {
+                Inst::Lookahead { negated, next }
+            });
+        },
+        Lookbehind { ref inner, negated } => {
+            // The parser rejects lookbehinds without a maximum length.
+            let (min_len, max_len) = inner.length_bounds();
+            let max_len = max_len.unwrap_or(usize::MAX);
+            let inner_insts = compile_regexp_offset(inner, offset + 1);
+            insts = sub_program_insts(offset, inner_insts, |next| {
+                Inst::Lookbehind { negated, next, min_len, max_len }
+            });
+        },
+        Capture { index, ref inner, .. } => {
+            let mut inner_insts = compile_regexp_offset(inner, offset + 1);
+            insts.push(Inst::Save(2 * index));
             insts.append(&mut inner_insts);
-            insts.push(jump_inst);
+            insts.push(Inst::Save(2 * index + 1));
+        },
+        OptionalRepeated(ref inner_regexp, greed) => {
+            insts = star_insts(inner_regexp, greed, offset);
         }
     }
     insts
 }
 
+/// Compiles `inner*` to start at `offset`, repeating as `greed` asks.
+///
+/// If `inner` can match empty this is `(?:inner+)?`. Repeating goes back
+/// to the start of `inner` rather than to the first `Split`, so that an
+/// iteration that matches empty moves on to the exit instead of reaching a
+/// pc already followed at this position. Otherwise each iteration jumps
+/// back to the first `Split`, as the `regex` crate compiles it, so that
+/// captures in nested loops come out the same.
+fn star_insts(inner: &Regexp, greed: Greed, offset: usize) -> Vec<Inst> {
+    let mut inner_insts = compile_regexp_offset(inner, offset + 1);
+    let exit_pc = offset + inner_insts.len() + 2;
+    let mut insts = vec![split(greed, offset + 1, exit_pc)];
+    insts.append(&mut inner_insts);
+    insts.push(if inner.length_bounds().0 == 0 {
+        split(greed, offset + 1, exit_pc)
+    } else {
+        Inst::Jump(offset)
+    });
+    insts
+}
+
 // struct State {
 //     c: Option<char>,
 //     action: Action
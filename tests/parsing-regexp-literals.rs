@@ -24,16 +24,23 @@ fn test_regexp_parser() {
 
 #[test]
 fn test_regexp_simplification() {
+    // Parentheses are capture groups, so they survive a round trip.
     let pairs = vec![
-        ("((a))", "a"),
-        ("((a|b))", "(a|b)"),
-        ("((a)(b))", "ab"),
-        ("((a?)(b)+)", "a?b+"),
-        ("(((a)?+|b(c+))|(((d)+)d+))", "((a?+|bc+)|d+d+)")
+        ("((a))", "((a))"),
+        ("((a|b))", "((a|b))"),
+        ("((a)(b))", "((a)(b))"),
+        ("((a?)(b)+)", "((a?)(b)+)"),
+        ("(((a)?+|b(c+))|(((d)+)d+))", "(((a)?+|b(c+))|(((d)+)d+))"),
+        ("a|bc|d", "a|bc|d"),
+        // Non-capturing groups only survive where they change the meaning.
+        ("(?:a)(?:b|c)", "a(?:b|c)"),
+        ("(?:ab)*(?:a*)+", "(?:ab)*(?:a*)+"),
+        ("(?:ab)c|(?:d|e)", "(?:ab)c|(?:d|e)"),
+        ("(?P<first>a)(?<second>b)", "(?<first>a)(?<second>b)"),
     ];
 
     for pair in pairs {
-        let regexp = Regexp::from_string(&(pair.0)).unwrap();
+        let regexp = Regexp::from_string(pair.0).unwrap();
         let regexp_string = regexp_to_string(&regexp);
         println!("\tinput:\t\t{},\n\
                   \texpected ouput:\t{}\n\
@@ -42,19 +49,658 @@ fn test_regexp_simplification() {
     }
 }
 
+#[test]
+fn test_empty_regexp_parsing() {
+    use regexp::Regexp::*;
+
+    assert_eq!(Regexp::from_string(""), Result::Ok(Empty));
+    assert_eq!(Regexp::from_string("(?i)(?#nothing)"), Result::Ok(Empty));
+    assert_eq!(Regexp::from_string("a|"),
+               Result::Ok(Alternation(vec![Char('a'), Empty])));
+    assert_eq!(Regexp::from_string("(|b)"), Result::Ok(Capture {
+        index: 1,
+        name: None,
+        inner: Box::new(Alternation(vec![Empty, Char('b')]))
+    }));
+    assert_eq!(Regexp::from_string("()"), Result::Ok(Capture {
+        index: 1,
+        name: None,
+        inner: Box::new(Empty)
+    }));
+    assert_eq!(Regexp::from_string("(?=)|(?<!)"), Result::Ok(Alternation(vec![
+        Lookahead { inner: Box::new(Empty), negated: false },
+        Lookbehind { inner: Box::new(Empty), negated: true }
+    ])));
+
+    let pairs = [
+        ("", ""),
+        ("||", "||"),
+        ("a(?:)b", "a(?:)b"),
+        ("(?:)*", "(?:)*"),
+        ("(?:|a)", "|a"),
+        ("x(?:|a)", "x(?:|a)"),
+    ];
+    for &(pattern, expected) in pairs.iter() {
+        let regexp = Regexp::from_string(pattern).unwrap();
+        assert_eq!(regexp_to_string(&regexp), expected);
+        assert_eq!(Regexp::from_string(expected), Result::Ok(regexp));
+    }
+}
+
+#[test]
+fn test_capture_group_numbering() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string("(a(b))+(c)").unwrap();
+    let expected = Concatenation(vec![
+        Repeated(Box::new(Capture {
+            index: 1,
+            name: None,
+            inner: Box::new(Concatenation(vec![
+                Char('a'),
+                Capture { index: 2, name: None, inner: Box::new(Char('b')) }
+            ]))
+        }), Greed::Greedy),
+        Capture { index: 3, name: None, inner: Box::new(Char('c')) }
+    ]);
+
+    assert_eq!(regexp, expected);
+
+    // Non-capturing groups are not numbered; named groups are numbered
+    // along with unnamed ones.
+    let regexp = Regexp::from_string("(?:a)(?P<x>b)(?:(c))").unwrap();
+    let expected = Concatenation(vec![
+        Char('a'),
+        Capture { index: 1, name: Some("x".to_string()),
+                  inner: Box::new(Char('b')) },
+        Capture { index: 2, name: None, inner: Box::new(Char('c')) }
+    ]);
+
+    assert_eq!(regexp, expected);
+}
+
+#[test]
+fn test_class_parsing() {
+    let regexp = Regexp::from_string("[^z_a-ec-g]").unwrap();
+    let expected = Regexp::Class {
+        class: CharClass::new(vec![('_', '_'), ('a', 'g'), ('z', 'z')]),
+        negated: true
+    };
+    assert_eq!(regexp, expected);
+    assert_eq!(regexp_to_string(&regexp), "[^_a-gz]");
+
+    let regexp = Regexp::from_string("[]-]+").unwrap();
+    assert_eq!(regexp_to_string(&regexp), "[\\-\\]]+");
+}
+
+#[test]
+fn test_dot_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string("a.*").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Char('a'),
+        OptionalRepeated(Box::new(AnyChar { newline: false }), Greed::Greedy)
+    ]));
+    assert_eq!(regexp_to_string(&regexp), "a.*");
+
+    let flags = Flags { dot_matches_new_line: true, ..Flags::default() };
+    let regexp = Regexp::from_string_with_flags(".", flags).unwrap();
+    assert_eq!(regexp, AnyChar { newline: true });
+    assert_eq!(regexp_to_string(&Char('.')), "\\.");
+}
+
+#[test]
+fn test_anchor_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string("^a$|\\Ab\\z").unwrap();
+    assert_eq!(regexp, Alternation(vec![
+        Concatenation(vec![Assertion(regexp::Assertion::StartText),
+                           Char('a'),
+                           Assertion(regexp::Assertion::EndText)]),
+        Concatenation(vec![Assertion(regexp::Assertion::StartText),
+                           Char('b'),
+                           Assertion(regexp::Assertion::EndText)])
+    ]));
+    assert_eq!(regexp_to_string(&regexp), "^a$|^b$");
+
+    let regexp = Regexp::from_string(r"\bx\B(?-u:\b)").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Assertion(regexp::Assertion::WordBoundary { unicode: true }),
+        Char('x'),
+        Assertion(regexp::Assertion::NotWordBoundary { unicode: true }),
+        Assertion(regexp::Assertion::WordBoundary { unicode: false })
+    ]));
+    assert_eq!(regexp_to_string(&regexp), r"\bx\B(?-u:\b)");
+
+    let flags = Flags { multi_line: true, ..Flags::default() };
+    let regexp = Regexp::from_string_with_flags("^a$", flags).unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Assertion(regexp::Assertion::StartLine),
+        Char('a'),
+        Assertion(regexp::Assertion::EndLine)
+    ]));
+}
+
+#[test]
+fn test_inline_flag_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string("(?s:.)(?m)^.(?-m:$)").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        AnyChar { newline: true },
+        Assertion(regexp::Assertion::StartLine),
+        AnyChar { newline: false },
+        Assertion(regexp::Assertion::EndText)
+    ]));
+    assert_eq!(regexp_to_string(&regexp), "(?s:.)(?m:^).$");
+
+    // A bare flag group lasts until the end of its enclosing group,
+    // including later alternatives.
+    let regexp = Regexp::from_string("((?s).|.).").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Capture { index: 1, name: None, inner: Box::new(Alternation(vec![
+            AnyChar { newline: true },
+            AnyChar { newline: true }
+        ])) },
+        AnyChar { newline: false }
+    ]));
+
+    let flags = Flags { dot_matches_new_line: true, ..Flags::default() };
+    let regexp = Regexp::from_string_with_flags("(?i-s).", flags).unwrap();
+    assert_eq!(regexp, AnyChar { newline: false });
+}
+
+#[test]
+fn test_case_insensitive_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string(r"(?i)ab1\d\p{Nd}[x]\w(?-u)k").unwrap();
+    let fold = |inner, unicode| CaseInsensitive {
+        inner: Box::new(inner), unicode
+    };
+    assert_eq!(regexp, Concatenation(vec![
+        fold(Char('a'), true),
+        fold(Char('b'), true),
+        Char('1'),
+        Perl { class: PerlClass::Digit, negated: false, unicode: true },
+        Property { name: "Nd".to_string(), negated: false },
+        fold(Class { class: CharClass::new(vec![('x', 'x')]), negated: false },
+             true),
+        Perl { class: PerlClass::Word, negated: false, unicode: true },
+        fold(Char('k'), false)
+    ]));
+    assert_eq!(regexp_to_string(&regexp),
+               r"(?i)ab(?-i)1\d\p{Nd}(?i:[x])\w(?i-u:k)");
+
+    let pairs = [
+        (r"(?i)(a)\1", r"((?i:a))(?i:\1)"),
+        (r"(?i)\Qab\E(?-u)cd", r"(?i)ab(?-i)(?i-u)cd(?u-i)"),
+        (r"(?i:ab)+", r"(?:(?i)ab(?-i))+"),
+        (r"a(?i)b", r"a(?i:b)"),
+    ];
+    for &(pattern, expected) in pairs.iter() {
+        let regexp = Regexp::from_string(pattern).unwrap();
+        assert_eq!(regexp_to_string(&regexp), expected);
+        assert_eq!(Regexp::from_string(expected), Result::Ok(regexp));
+    }
+}
+
+#[test]
+fn test_escape_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string(r"\(\n\t\x41\x{3bb}\u{1F600}\0\.\\\]")
+        .unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Char('('), Char('\n'), Char('\t'), Char('A'), Char('λ'),
+        Char('😀'), Char('\0'), Char('.'), Char('\\'), Char(']')
+    ]));
+    assert_eq!(regexp_to_string(&regexp), r"\(\n\tAλ😀\x{0}\.\\]");
+
+    let regexp = Regexp::from_string(r"[\]\-\\\x00-\x1F^]").unwrap();
+    assert_eq!(regexp, Class {
+        class: CharClass::new(vec![('\0', '\u{1F}'), (']', ']'), ('-', '-'),
+                                   ('\\', '\\'), ('^', '^')]),
+        negated: false
+    });
+    assert_eq!(regexp_to_string(&regexp), r"[\x{0}-\x{1F}\-\\-\^]");
+
+    let text = "1+1=2? (a|b) [x] {3} ^$ \\ \t.*";
+    let pattern = escape(text);
+    assert_eq!(pattern,
+               r"1\+1=2\?\ \(a\|b\)\ \[x]\ \{3}\ \^\$\ \\\ \t\.\*");
+    assert_eq!(Regexp::from_string(&pattern).unwrap(),
+               Concatenation(text.chars().map(Char).collect()));
+
+    // Escaped text means the same in verbose mode.
+    let text = "a #b\u{3000}c\n";
+    let pattern = escape(text);
+    assert_eq!(pattern, r"a\ \#b\x{3000}c\n");
+    assert_eq!(Regexp::from_string(&format!("(?x){}", pattern)).unwrap(),
+               Concatenation(text.chars().map(Char).collect()));
+}
+
+#[test]
+fn test_perl_class_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string(r"\d\S(?-u)\w\W").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Perl { class: PerlClass::Digit, negated: false, unicode: true },
+        Perl { class: PerlClass::Space, negated: true, unicode: true },
+        Perl { class: PerlClass::Word, negated: false, unicode: false },
+        Perl { class: PerlClass::Word, negated: true, unicode: false }
+    ]));
+    assert_eq!(regexp_to_string(&regexp), r"\d\S(?-u:\w)(?-u:\W)");
+
+    let regexp = Regexp::from_string(r"(?-u)[\s\dx]").unwrap();
+    assert_eq!(regexp, Class {
+        class: CharClass::new(vec![('\t', '\r'), (' ', ' '), ('0', '9'),
+                                   ('x', 'x')]),
+        negated: false
+    });
+}
+
+#[test]
+fn test_posix_class_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string("[[:digit:][:upper:]_]").unwrap();
+    assert_eq!(regexp, Class {
+        class: CharClass::new(vec![('0', '9'), ('A', 'Z'), ('_', '_')]),
+        negated: false
+    });
+    assert_eq!(regexp_to_string(&regexp), "[0-9A-Z_]");
+
+    let regexp = Regexp::from_string("[^[:^alpha:]]").unwrap();
+    assert_eq!(regexp, Class {
+        class: posix_class("alpha").unwrap().negate(),
+        negated: true
+    });
+
+    let regexp = Regexp::from_string("[[=A=]]").unwrap();
+    assert_eq!(regexp, Class { class: equivalence_class('A'), negated: false });
+    assert!(equivalence_class('A').contains('Å'));
+    assert!(equivalence_class('A').contains('\u{212B}'));
+    assert!(!equivalence_class('A').contains('a'));
+    assert_eq!(equivalence_class('Ǻ'), equivalence_class('A'));
+    assert_eq!(equivalence_class('!'), CharClass::new(vec![('!', '!')]));
+
+    let regexp = Regexp::from_string(r"[\[:alpha\[=ab=]").unwrap();
+    assert_eq!(regexp, Class {
+        class: CharClass::new(vec![(':', ':'), ('=', '='), ('[', '['),
+                                   ('a', 'b'), ('h', 'h'), ('l', 'l'),
+                                   ('p', 'p')]),
+        negated: false
+    });
+}
+
+#[test]
+fn test_class_set_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string(r"[\w--\d]").unwrap();
+    assert_eq!(regexp, Class {
+        class: PerlClass::Word.to_class(true)
+            .difference(&PerlClass::Digit.to_class(true)),
+        negated: false
+    });
+
+    let regexp = Regexp::from_string("[^a-z&&[^aeiou]x~~[y-z]]").unwrap();
+    assert_eq!(regexp, Class {
+        class: CharClass::new(vec![('b', 'd'), ('f', 'h'), ('j', 'n'),
+                                   ('p', 't'), ('v', 'x')]),
+        negated: true
+    });
+    assert_eq!(regexp_to_string(&regexp), "[^b-df-hj-np-tv-x]");
+
+    let regexp = Regexp::from_string("[a&&b]").unwrap();
+    assert_eq!(regexp, Class { class: CharClass::empty(), negated: false });
+    assert_eq!(regexp_to_string(&regexp), "[a&&b]");
+    let regexp = Regexp::from_string("[^[a]&&[b]]").unwrap();
+    assert_eq!(regexp_to_string(&regexp), "[^a&&b]");
+
+    let regexp = Regexp::from_string("[]a-&&[]-]]").unwrap();
+    assert_eq!(regexp, Class {
+        class: CharClass::new(vec![('-', '-'), (']', ']')]),
+        negated: false
+    });
+
+    // An operator with nothing on one side is literal.
+    let pairs = [
+        ("[--a]", vec![('-', 'a')]),
+        ("[&&a]", vec![('&', '&'), ('a', 'a')]),
+        ("[a&&]", vec![('&', '&'), ('a', 'a')]),
+        ("[^~~]", vec![('~', '~')]),
+        ("[a--]", vec![('-', '-'), ('a', 'a')]),
+        ("[a-c&&&&b]", vec![('b', 'b')]),
+        ("[a-c&&&]", vec![]),
+    ];
+    for pair in pairs.iter() {
+        let regexp = Regexp::from_string(pair.0).unwrap();
+        let class = match regexp {
+            Class { class, .. } => class,
+            _ => panic!("{} is not a class", pair.0)
+        };
+        assert_eq!(class, CharClass::new(pair.1.clone()), "{}", pair.0);
+    }
+}
+
+#[test]
+fn test_property_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string(r"\p{Greek}\PL\p{Lu}").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Property { name: "Greek".to_string(), negated: false },
+        Property { name: "L".to_string(), negated: true },
+        Property { name: "Lu".to_string(), negated: false }
+    ]));
+    assert_eq!(regexp_to_string(&regexp), r"\p{Greek}\P{L}\p{Lu}");
+
+    let regexp = Regexp::from_string(r"[\p{Nd}\P{Any_Unknown_Thing}]");
+    assert_eq!(regexp, Result::Err(RegexpError::UnknownProperty(7)));
+    let regexp = Regexp::from_string(r"[a\p{Zs}]").unwrap();
+    assert_eq!(regexp, Class {
+        class: unicode_property("Zs").unwrap()
+            .union(&CharClass::new(vec![('a', 'a')])),
+        negated: false
+    });
+}
+
+#[test]
+fn test_backreference_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string(r"(?<a>x)(y)\2\k<a>").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Capture { index: 1, name: Some("a".to_string()),
+                  inner: Box::new(Char('x')) },
+        Capture { index: 2, name: None, inner: Box::new(Char('y')) },
+        Backreference(2),
+        Backreference(1)
+    ]));
+    assert_eq!(regexp_to_string(&regexp), r"(?<a>x)(y)\2\1");
+
+    // A literal digit after a backreference is kept apart from it.
+    let regexp = Concatenation(vec![
+        Capture { index: 1, name: None, inner: Box::new(Char('a')) },
+        Backreference(1),
+        Char('0')
+    ]);
+    let text = regexp_to_string(&regexp);
+    assert_eq!(text, r"(a)(?:\1)0");
+    assert_eq!(Regexp::from_string(&text).unwrap(), regexp);
+}
+
+#[test]
+fn test_lookaround_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string(r"(?=a)(?!b)(?<=c)(?<!d)(?<e>f)")
+        .unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Lookahead { inner: Box::new(Char('a')), negated: false },
+        Lookahead { inner: Box::new(Char('b')), negated: true },
+        Lookbehind { inner: Box::new(Char('c')), negated: false },
+        Lookbehind { inner: Box::new(Char('d')), negated: true },
+        Capture { index: 1, name: Some("e".to_string()),
+                  inner: Box::new(Char('f')) }
+    ]));
+    assert_eq!(regexp_to_string(&regexp), r"(?=a)(?!b)(?<=c)(?<!d)(?<e>f)");
+
+    let bounds = |pattern| Regexp::from_string(pattern).unwrap()
+        .length_bounds();
+    assert_eq!(bounds(r"ab?\d{2,3}"), (3, Some(5)));
+    assert_eq!(bounds(r"(a|bcd)(?=x+)\b"), (1, Some(3)));
+    assert_eq!(bounds(r"a(b*)"), (1, None));
+    assert_eq!(bounds(r"(?:\b)*x{3,}"), (3, None));
+}
+
+#[test]
+fn test_verbose_mode_parsing() {
+    use regexp::Regexp::*;
+
+    let verbose = Regexp::from_string("(?x)
+        ^ \\d+       # digits
+        ( \\. \\d+ )? # then maybe a fraction
+        [ ]kg $
+    ").unwrap();
+    let compact = Regexp::from_string(r"^\d+(\.\d+)?[ ]kg$").unwrap();
+    assert_eq!(verbose, compact);
+
+    let flags = Flags { ignore_whitespace: true, ..Flags::default() };
+    let regexp = Regexp::from_string_with_flags("a b(?-x) c", flags).unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Char('a'), Char('b'), Char(' '), Char('c')
+    ]));
+
+    let regexp = Regexp::from_string("a(?#one)(?#two)|b(?#)").unwrap();
+    assert_eq!(regexp, Alternation(vec![Char('a'), Char('b')]));
+}
+
+#[test]
+fn test_pretty_printing() {
+    let regexp = Regexp::from_string(
+        r"^(?<year>\d{4})-(\d\d)(?:T(\d\d):(\d\d)|Z)?$").unwrap();
+    assert_eq!(regexp_to_pretty_string(&regexp, 16), "(?x)
+^(?<year>\\d{4})-
+(\\d\\d)
+(?:
+      T(\\d\\d):
+      (\\d\\d)
+    |
+      Z
+)?
+$");
+    assert_eq!(regexp_to_pretty_string(&regexp, 80),
+               r"(?x)
+^(?<year>\d{4})-(\d\d)(?:T(\d\d):(\d\d)|Z)?$");
+
+    let patterns = [
+        r"a b#c\x{A0}d",
+        r"(a|b c)(?:d|e)*\1 [ #]",
+        r"(a)\1(?:2)|(?i)x(?s:.)(?m:^$)",
+        r"(?:(?:ab|cd)|ef)+?(?>gh|ij)++",
+        r"(?<=foo|ba)(?!x y)(?<!z)(?=\w+\b)",
+        r"(?-u:\w\b)\p{Greek}+[^a-z ]{2,5}",
+        r"((((a|b)|c)d)(e(f|g))|hij(?:k|l)*)",
+        r"\Q((a + b)*c) #\E|x",
+        r"(?i)(?<w>a b#)\k<w>[c-e]|(?-u)xy z",
+        r"a||(|b)(?:)c|",
+        "",
+    ];
+    for pattern in patterns.iter() {
+        let regexp = Regexp::from_string(pattern).unwrap();
+        for &width in [0, 8, 16, 40, 80].iter() {
+            let pretty = regexp_to_pretty_string(&regexp, width);
+            assert_eq!(Regexp::from_string(&pretty).as_ref(), Ok(&regexp),
+                       "{:?} at width {}:\n{}", pattern, width, pretty);
+        }
+    }
+}
+
+#[test]
+fn test_quote_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string(r"\Qa+(b)\E+c").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Char('a'), Char('+'), Char('('), Char('b'),
+        Repeated(Box::new(Char(')')), Greed::Greedy), Char('c')
+    ]));
+
+    let regexp = Regexp::from_string(r"x\Q\E*\Q|\").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        OptionalRepeated(Box::new(Char('x')), Greed::Greedy),
+        Char('|'), Char('\\')
+    ]));
+
+    let text = "((a+b)*c)";
+    let regexp = Regexp::from_string(&escape(text)).unwrap();
+    assert_eq!(regexp_to_string(&regexp), r"\Q((a+b)*c)\E");
+    assert_eq!(escape(text), r"\(\(a\+b\)\*c\)");
+    let regexp = Regexp::from_string(r"\Q((a+b)*c)\E\1?").unwrap_err();
+    assert_eq!(regexp, RegexpError::InvalidBackreference(13));
+
+    let pairs = [
+        (r"\Qa+b(c)\E", r"a\+b\(c\)"),
+        (r"(a)x\Q((a+b)*c)\E+", r"(a)\Qx((a+b)*c\E\)+"),
+        (r"\Q((\E\\E\Q))\E", r"\(\(\\E\)\)"),
+        (r"(?x)\Q(( a+ ))\E", r"\Q(( a+ ))\E"),
+    ];
+    for &(pattern, expected) in pairs.iter() {
+        let regexp = Regexp::from_string(pattern).unwrap();
+        assert_eq!(regexp_to_string(&regexp), expected);
+        assert_eq!(Regexp::from_string(expected), Result::Ok(regexp));
+    }
+}
+
+#[test]
+fn test_counted_repetition_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string("a{2}b{3,}c{0,5}").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Repeat { inner: Box::new(Char('a')), min: 2, max: Some(2),
+                 greed: Greed::Greedy },
+        Repeat { inner: Box::new(Char('b')), min: 3, max: None,
+                 greed: Greed::Greedy },
+        Repeat { inner: Box::new(Char('c')), min: 0, max: Some(5),
+                 greed: Greed::Greedy }
+    ]));
+    assert_eq!(regexp_to_string(&regexp), "a{2}b{3,}c{0,5}");
+}
+
+#[test]
+fn test_lazy_quantifier_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string("a??b+?c*?d{2,}?").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Optional(Box::new(Char('a')), Greed::Lazy),
+        Repeated(Box::new(Char('b')), Greed::Lazy),
+        OptionalRepeated(Box::new(Char('c')), Greed::Lazy),
+        Repeat { inner: Box::new(Char('d')), min: 2, max: None,
+                 greed: Greed::Lazy }
+    ]));
+    assert_eq!(regexp_to_string(&regexp), "a??b+?c*?d{2,}?");
+
+    // A greedy quantifier under a `?` keeps its own group when printed.
+    let nested = Optional(Box::new(Repeated(Box::new(Char('a')),
+                                            Greed::Greedy)),
+                          Greed::Greedy);
+    assert_eq!(regexp_to_string(&nested), "(?:a+)?");
+}
+
+#[test]
+fn test_atomic_and_possessive_parsing() {
+    use regexp::Regexp::*;
+
+    let regexp = Regexp::from_string("a?+b++(?>c*+d{2}+)").unwrap();
+    assert_eq!(regexp, Concatenation(vec![
+        Optional(Box::new(Char('a')), Greed::Possessive),
+        Repeated(Box::new(Char('b')), Greed::Possessive),
+        Atomic(Box::new(Concatenation(vec![
+            OptionalRepeated(Box::new(Char('c')), Greed::Possessive),
+            Repeat { inner: Box::new(Char('d')), min: 2, max: Some(2),
+                     greed: Greed::Possessive }
+        ])))
+    ]));
+    assert_eq!(regexp_to_string(&regexp), "a?+b++(?>c*+d{2}+)");
+
+    // A greedy quantifier under a `+` keeps its own group when printed.
+    let nested = Repeated(Box::new(OptionalRepeated(Box::new(Char('a')),
+                                                    Greed::Greedy)),
+                          Greed::Greedy);
+    assert_eq!(regexp_to_string(&nested), "(?:a*)+");
+
+    assert_eq!(Regexp::from_string("a(?>)"),
+               Result::Ok(Concatenation(vec![Char('a'),
+                                             Atomic(Box::new(Empty))])));
+}
 
 #[test]
 fn test_regexs_error_detection() {
     use regexp::RegexpError::*;
     
-    let pairs = vec![
+    let pairs = [
         ("((a)", UnmatchedParenthesis(0)),
         ("(a))", UnmatchedParenthesis(3)),
         ("(((a))((b())", UnmatchedParenthesis(6)),
+        ("a[bc", UnterminatedClass(1)),
+        ("[^]", UnterminatedClass(0)),
+        ("([a)]", UnmatchedParenthesis(0)),
+        ("ab[x-az]", ReversedRange(3)),
+        ("a{", InvalidRepetition(1)),
+        ("a{,3}", InvalidRepetition(1)),
+        ("a{3", InvalidRepetition(1)),
+        ("a{3,x}", InvalidRepetition(1)),
+        ("a{99999999999}", InvalidRepetition(1)),
+        ("ab{3,2}", ReversedRepetition(2)),
+        ("x{4294967295}", RepetitionTooLarge(1)),
+        ("x{1001}", RepetitionTooLarge(1)),
+        ("ab{2,1001}", RepetitionTooLarge(2)),
+        ("((((a{100}){100}){100}){100})", PatternTooLarge),
+        ("(?:(a{1000}){1000}){2}", PatternTooLarge),
+        ("{3}", MisplacedOperator(0)),
+        ("(?", UnmatchedParenthesis(0)),
+        ("a(?:b", UnmatchedParenthesis(1)),
+        ("a(?%b)", UnknownGroup(1)),
+        ("(?P<>a)", InvalidGroupName(4)),
+        ("(?<1a>a)", InvalidGroupName(3)),
+        ("(?<a-b>a)", InvalidGroupName(3)),
+        ("(?P<name", InvalidGroupName(4)),
+        ("(?<x>a)(?P<x>b)", DuplicateGroupName(11)),
+        ("a(?q)", UnknownFlag(3)),
+        ("(?i-m-s:a)", UnknownFlag(5)),
+        ("(?)", UnknownGroup(0)),
+        ("(?i", UnmatchedParenthesis(0)),
+        ("a(?i)*", MisplacedOperator(5)),
+        ("ab\\", UnknownEscape(2)),
+        ("a\\q", UnknownEscape(1)),
+        ("[a\\y]", UnknownEscape(2)),
+        ("\\x4", InvalidHexEscape(0)),
+        ("\\xg0", InvalidHexEscape(0)),
+        ("a\\x{110000}", InvalidHexEscape(1)),
+        ("\\x{d800}", InvalidHexEscape(0)),
+        ("\\x{41", InvalidHexEscape(0)),
+        ("\\u0041", InvalidHexEscape(0)),
+        ("[\\x{7A}-a]", ReversedRange(1)),
+        ("a\\p{Klingon}", UnknownProperty(1)),
+        ("\\p{L", UnknownProperty(0)),
+        ("\\p", UnknownProperty(0)),
+        ("\\pZ\\pX", UnknownProperty(3)),
+        ("[[:word:]]", UnknownClassName(1)),
+        ("a[b[:^Alpha:]]", UnknownClassName(3)),
+        ("[[:alpha:]", UnterminatedClass(0)),
+        ("[a[b]", UnterminatedClass(0)),
+        ("[a&&[b", UnterminatedClass(4)),
+        ("[a&&[z-b]]", ReversedRange(5)),
+        ("a\\1", InvalidBackreference(1)),
+        ("(a)\\2", InvalidBackreference(3)),
+        ("(a)\\10", InvalidBackreference(3)),
+        ("\\1(a)", InvalidBackreference(0)),
+        ("(?<a>x)\\k<b>", InvalidBackreference(7)),
+        ("(?<a>x)\\k<a", InvalidBackreference(7)),
+        ("(a)[\\1]", UnknownEscape(4)),
+        ("a(?<=b+)", UnboundedLookbehind(1)),
+        ("(?<!a|b*)", UnboundedLookbehind(0)),
+        ("(a)(?<=\\1)", UnboundedLookbehind(3)),
+        ("(?<=(?<!a)b{2,})", UnboundedLookbehind(0)),
+        ("(?<=a+)(", UnmatchedParenthesis(7)),
+        // Errors point into the pattern as written, comments and all.
+        ("(?x) a  b [z-a]", ReversedRange(11)),
+        ("(?x) a # ( \n )", UnmatchedParenthesis(13)),
+        ("a(?#b)c)", UnmatchedParenthesis(7)),
+        ("a(?#b", UnmatchedParenthesis(1)),
+        ("(?x)a \\q", UnknownEscape(6)),
     ];
 
     for pair in pairs.iter() {
-        let err = Regexp::from_string(&(pair.0)).unwrap_err();
+        let err = Regexp::from_string(pair.0).unwrap_err();
         assert_eq!(pair.1, err);
     }
 }